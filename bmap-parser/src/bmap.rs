@@ -1,3 +1,4 @@
+use std::ops::Range;
 use strum::{Display, EnumDiscriminants, EnumString};
 use thiserror::Error;
 mod xml;
@@ -34,6 +35,18 @@ pub struct BlockRange {
     offset: u64,
     length: u64,
     checksum: HashValue,
+    // Bytes covered by the checksum directly before and after the range, for ranges clipped
+    // by restricting the map
+    lead: u64,
+    trail: u64,
+    // Bytes directly before and after the range which are copied from the input along with it
+    // without being covered by the checksum, to make writes line up with the output. Never set
+    // together with lead and trail respectively
+    pad_before: u64,
+    pad_after: u64,
+    // Whether the padding after the range bridges the gap to the next range, the two being
+    // coalesced into a single merged range
+    bridged: bool,
 }
 
 impl BlockRange {
@@ -48,9 +61,40 @@ impl BlockRange {
     pub fn length(&self) -> u64 {
         self.length
     }
+
+    /// Whether the range got clipped when restricting its map, in which case the checksum
+    /// covers more data than the range itself
+    pub fn is_clipped(&self) -> bool {
+        self.lead > 0 || self.trail > 0
+    }
+
+    /// Bytes of the output written when copying the range, including any padding
+    pub fn write_range(&self) -> Range<u64> {
+        self.offset - self.pad_before..self.offset + self.length + self.pad_after
+    }
+
+    pub(crate) fn lead(&self) -> u64 {
+        self.lead
+    }
+
+    pub(crate) fn trail(&self) -> u64 {
+        self.trail
+    }
+
+    pub(crate) fn pad_before(&self) -> u64 {
+        self.pad_before
+    }
+
+    pub(crate) fn pad_after(&self) -> u64 {
+        self.pad_after
+    }
+
+    pub(crate) fn is_bridged(&self) -> bool {
+        self.bridged
+    }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Bmap {
     image_size: u64,
     block_size: u64,
@@ -58,6 +102,7 @@ pub struct Bmap {
     mapped_blocks: u64,
     checksum_type: HashType,
     blockmap: Vec<BlockRange>,
+    source_offset: u64,
 }
 
 impl Bmap {
@@ -95,6 +140,12 @@ impl Bmap {
         self.checksum_type
     }
 
+    /// Offset in the input at which the image described by this map starts. Only non-zero for
+    /// maps restricted to part of the original image
+    pub fn source_offset(&self) -> u64 {
+        self.source_offset
+    }
+
     /// Iterator over the block map
     pub fn block_map(&self) -> impl ExactSizeIterator<Item = &BlockRange> {
         self.blockmap.iter()
@@ -104,6 +155,193 @@ impl Bmap {
     pub fn total_mapped_size(&self) -> u64 {
         self.block_size * self.mapped_blocks
     }
+
+    /// Offset just past the last mapped byte of the image
+    pub fn mapped_end(&self) -> u64 {
+        self.blockmap.last().map_or(0, |r| r.write_range().end)
+    }
+
+    /// Iterator over the byte ranges not covered by the block map, including the
+    /// range between the last mapped block and the end of the image
+    pub fn unmapped_ranges(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        let mut position = 0;
+        let mut ranges = self.blockmap.iter();
+        std::iter::from_fn(move || {
+            for range in ranges.by_ref() {
+                let start = position;
+                let written = range.write_range();
+                position = written.end;
+                if written.start > start {
+                    return Some(start..written.start);
+                }
+            }
+            if position < self.image_size {
+                let start = position;
+                position = self.image_size;
+                return Some(start..self.image_size);
+            }
+            None
+        })
+    }
+
+    /// Restrict the map to `length` bytes of the image starting at `offset`, e.g. a single
+    /// partition, with the offsets of the remaining ranges rebased to the start of that window.
+    ///
+    /// Ranges straddling the boundaries of the window get clipped. As their checksum still
+    /// covers the full original range, that gets read and verified when copying while only the
+    /// part inside the window is written.
+    pub fn restrict(&self, offset: u64, length: u64) -> Bmap {
+        let end = offset.saturating_add(length).min(self.image_size);
+        let offset = offset.min(end);
+        let blockmap: Vec<BlockRange> = self
+            .blockmap
+            .iter()
+            .filter_map(|r| {
+                let r_end = r.offset + r.length;
+                if r_end <= offset || r.offset >= end {
+                    return None;
+                }
+                let start = r.offset.max(offset);
+                let stop = r_end.min(end);
+                let written = r.write_range();
+                Some(BlockRange {
+                    offset: start - offset,
+                    length: stop - start,
+                    checksum: r.checksum,
+                    lead: r.lead + start - r.offset,
+                    trail: r.trail + r_end - stop,
+                    pad_before: start - written.start.clamp(offset, start),
+                    pad_after: written.end.clamp(stop, end) - stop,
+                    bridged: false,
+                })
+            })
+            .collect();
+
+        Bmap {
+            image_size: end - offset,
+            block_size: self.block_size,
+            blocks: 0,
+            mapped_blocks: 0,
+            checksum_type: self.checksum_type,
+            blockmap,
+            source_offset: self.source_offset + offset,
+        }
+        .recount()
+    }
+
+    /// Pad the ranges with the data around them such that writes start and end on multiples of
+    /// `unit`, e.g. the erase block size of flash media, given the image gets written `origin`
+    /// bytes into the output. Ranges whose padded writes would meet get merged into a single
+    /// write, with the data in between copied along unverified. Each range keeps being verified
+    /// against its own checksum.
+    pub fn align_writes(&self, unit: u64, origin: u64) -> Bmap {
+        let mut aligned = self.clone();
+        if unit == 0 {
+            return aligned;
+        }
+        let blockmap = &mut aligned.blockmap;
+        let aligned_start = |r: &BlockRange| {
+            let start = r.offset - r.pad_before;
+            start - ((origin + start) % unit).min(start)
+        };
+        let aligned_end = |r: &BlockRange| {
+            let end = r.offset + r.length + r.pad_after;
+            (origin + end)
+                .next_multiple_of(unit)
+                .saturating_sub(origin)
+                .min(self.image_size)
+                .max(end)
+        };
+
+        for i in 0..blockmap.len() {
+            if i == 0 && blockmap[i].lead == 0 {
+                blockmap[i].pad_before = blockmap[i].offset - aligned_start(&blockmap[i]);
+            }
+            if blockmap[i].trail > 0 {
+                continue;
+            }
+            let end = aligned_end(&blockmap[i]);
+            let range_end = blockmap[i].offset + blockmap[i].length;
+            let next = blockmap
+                .get(i + 1)
+                .map(|next| (next.write_range().start, aligned_start(next)));
+            match next {
+                // Bridge the gap to the next range, which needs no padding in front anymore
+                Some((start, aligned)) if aligned <= end => {
+                    blockmap[i].pad_after = start - range_end
+                }
+                Some((_, aligned)) => {
+                    blockmap[i].pad_after = end - range_end;
+                    blockmap[i + 1].pad_before = blockmap[i + 1].offset - aligned;
+                }
+                None => blockmap[i].pad_after = end - range_end,
+            }
+        }
+
+        aligned.recount()
+    }
+
+    /// Merge ranges separated by gaps smaller than `max_gap` bytes into single writes, trading
+    /// copying the data in between for fewer seeks. The bridged data is streamed through from the
+    /// input while copying, with each range still verified against its own checksum and the
+    /// checksums of the merged ranges computed along, see [`crate::CopyReport::coalesced`].
+    /// Clipped ranges are left alone, as their checksums cover data outside the map.
+    pub fn coalesce(&self, max_gap: u64) -> Bmap {
+        let mut coalesced = self.clone();
+        let blockmap = &mut coalesced.blockmap;
+        for i in 1..blockmap.len() {
+            let (prev, next) = (&blockmap[i - 1], &blockmap[i]);
+            let gap = next.write_range().start - prev.write_range().end;
+            if gap < max_gap && !prev.is_clipped() && !next.is_clipped() {
+                blockmap[i - 1].pad_after += gap + blockmap[i].pad_before;
+                blockmap[i - 1].bridged = true;
+                blockmap[i].pad_before = 0;
+            }
+        }
+        coalesced.recount()
+    }
+
+    /// Replace each group of bridged ranges, given as ranges of indices into the block map, by a
+    /// single range spanning the data in between as well, covered by the given checksum
+    pub(crate) fn merge(&self, groups: &[(Range<usize>, HashValue)]) -> Bmap {
+        let mut blockmap = Vec::new();
+        let mut next = 0;
+        for (group, checksum) in groups {
+            blockmap.extend_from_slice(&self.blockmap[next..group.start]);
+            let first = &self.blockmap[group.start];
+            let last = &self.blockmap[group.end - 1];
+            blockmap.push(BlockRange {
+                offset: first.offset,
+                length: last.offset + last.length - first.offset,
+                checksum: *checksum,
+                lead: 0,
+                trail: 0,
+                pad_before: first.pad_before,
+                pad_after: last.pad_after,
+                bridged: false,
+            });
+            next = group.end;
+        }
+        blockmap.extend_from_slice(&self.blockmap[next..]);
+
+        Bmap {
+            blockmap,
+            ..self.clone()
+        }
+        .recount()
+    }
+
+    /// Update the block counts after changing the block map
+    fn recount(mut self) -> Self {
+        let written: u64 = self
+            .blockmap
+            .iter()
+            .map(|r| r.write_range().end - r.write_range().start)
+            .sum();
+        self.blocks = self.image_size.div_ceil(self.block_size);
+        self.mapped_blocks = written.div_ceil(self.block_size);
+        self
+    }
 }
 
 #[derive(Clone, Debug, Error)]
@@ -171,6 +409,11 @@ impl BmapBuilder {
             offset,
             length,
             checksum,
+            lead: 0,
+            trail: 0,
+            pad_before: 0,
+            pad_after: 0,
+            bridged: false,
         };
         self.blockmap.push(range);
         self
@@ -195,6 +438,7 @@ impl BmapBuilder {
             mapped_blocks,
             checksum_type,
             blockmap,
+            source_offset: 0,
         })
     }
 }
@@ -0,0 +1,112 @@
+use crate::SeekForward;
+use std::io::Result as IOResult;
+use std::io::{Error, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread::JoinHandle;
+
+/// Number of writes queued per output before a write on the `FanOut` blocks
+const QUEUE_DEPTH: usize = 4;
+
+enum Op {
+    Write(Arc<[u8]>),
+    Seek(u64),
+    Flush,
+}
+
+struct Output<W> {
+    sender: Option<SyncSender<Op>>,
+    thread: JoinHandle<(W, IOResult<()>)>,
+}
+
+fn run<W: Write + SeekForward>(mut output: W, receiver: Receiver<Op>) -> (W, IOResult<()>) {
+    for op in receiver {
+        let r = match op {
+            Op::Write(data) => output.write_all(&data),
+            Op::Seek(forward) => output.seek_forward(forward),
+            Op::Flush => output.flush(),
+        };
+        if let Err(e) = r {
+            // Dropping the receiver lets the FanOut know this output is gone
+            return (output, Err(e));
+        }
+    }
+    let r = output.flush();
+    (output, r)
+}
+
+/// Adaptor duplicating writes and forward seeks to multiple outputs, such that the input only
+/// needs to be decoded and verified once.
+///
+/// Every output is driven from its own thread so writes happen concurrently. An output failing
+/// is dropped without affecting the others. Writing to the `FanOut` itself only fails once all
+/// outputs have failed. Slow outputs do throttle the others as only a limited amount of data is
+/// queued for each output.
+pub struct FanOut<W> {
+    outputs: Vec<Output<W>>,
+}
+
+impl<W: Write + SeekForward + Send + 'static> FanOut<W> {
+    pub fn new<T: IntoIterator<Item = W>>(outputs: T) -> Self {
+        let outputs = outputs
+            .into_iter()
+            .map(|output| {
+                let (sender, receiver) = sync_channel(QUEUE_DEPTH);
+                let thread = std::thread::spawn(move || run(output, receiver));
+                Output {
+                    sender: Some(sender),
+                    thread,
+                }
+            })
+            .collect();
+        Self { outputs }
+    }
+
+    /// Wait for all outputs to finish their queued work, returning them in the order they were
+    /// given along with the first error each of them hit, if any
+    pub fn finish(self) -> Vec<(W, IOResult<()>)> {
+        self.outputs
+            .into_iter()
+            .map(|Output { sender, thread }| {
+                drop(sender);
+                thread.join().expect("Output thread panicked")
+            })
+            .collect()
+    }
+
+    fn send<F: Fn() -> Op>(&mut self, op: F) -> IOResult<()> {
+        let mut alive = false;
+        for output in self.outputs.iter_mut() {
+            if let Some(sender) = &output.sender {
+                if sender.send(op()).is_ok() {
+                    alive = true;
+                } else {
+                    output.sender = None;
+                }
+            }
+        }
+        if alive {
+            Ok(())
+        } else {
+            Err(Error::other("All outputs failed"))
+        }
+    }
+}
+
+impl<W: Write + SeekForward + Send + 'static> Write for FanOut<W> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let data: Arc<[u8]> = Arc::from(buf);
+        self.send(|| Op::Write(data.clone()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        self.send(|| Op::Flush)
+    }
+}
+
+impl<W: Write + SeekForward + Send + 'static> SeekForward for FanOut<W> {
+    fn seek_forward(&mut self, forward: u64) -> IOResult<()> {
+        self.send(|| Op::Seek(forward))
+    }
+}
@@ -2,14 +2,29 @@ mod bmap;
 pub use crate::bmap::*;
 mod discarder;
 pub use crate::discarder::*;
+mod fanout;
+pub use crate::fanout::*;
+mod measure;
+use crate::measure::{Measured, TimedHasher};
+mod options;
+pub use crate::options::*;
+mod partition;
+pub use crate::partition::*;
+mod ratelimit;
+use crate::ratelimit::RateLimited;
+mod report;
+pub use crate::report::*;
+mod sparse;
+pub use crate::sparse::*;
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use futures::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
-use sha2::{Digest, Sha256};
 use thiserror::Error;
 
 use std::io::Result as IOResult;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::Instant;
 
 /// Trait that can only seek further forwards
 pub trait SeekForward {
@@ -18,6 +33,10 @@ pub trait SeekForward {
 
 impl<T: Seek> SeekForward for T {
     fn seek_forward(&mut self, forward: u64) -> IOResult<()> {
+        // Not seeking at all keeps streams like pipes working where nothing gets skipped
+        if forward == 0 {
+            return Ok(());
+        }
         self.seek(SeekFrom::Current(forward as i64))?;
         Ok(())
     }
@@ -31,6 +50,9 @@ pub trait AsyncSeekForward {
 #[async_trait]
 impl<T: AsyncSeek + Unpin + Send> AsyncSeekForward for T {
     async fn async_seek_forward(&mut self, forward: u64) -> IOResult<()> {
+        if forward == 0 {
+            return Ok(());
+        }
         self.seek(SeekFrom::Current(forward as i64)).await?;
         Ok(())
     }
@@ -46,29 +68,431 @@ pub enum CopyError {
     ChecksumError,
     #[error("Unexpected EOF on input")]
     UnexpectedEof,
+    #[error("Writing up to byte {end} exceeds the destination size of {size} bytes")]
+    OutOfBounds { end: u64, size: u64 },
+}
+
+static ZEROES: [u8; 1024 * 1024] = [0; 1024 * 1024];
+
+/// Blocks skipped after failing to be written, when continuing on write errors
+struct Recovery {
+    block_size: u64,
+    failed: Vec<Range<u64>>,
+}
+
+impl Recovery {
+    fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            failed: Vec::new(),
+        }
+    }
+
+    /// Split data at `offset` in the image into the blocks to retry it in
+    fn blocks(&self, offset: u64, len: usize) -> impl Iterator<Item = Range<usize>> + use<> {
+        let block_size = self.block_size;
+        let mut start = 0;
+        std::iter::from_fn(move || {
+            if start == len {
+                return None;
+            }
+            let pos = offset + start as u64;
+            let end = len.min(start + (block_size - pos % block_size) as usize);
+            let block = start..end;
+            start = end;
+            Some(block)
+        })
+    }
+
+    /// Record a failed write, as the whole block it's part of
+    fn record(&mut self, failed: Range<u64>) {
+        let start = failed.start - failed.start % self.block_size;
+        match self.failed.last_mut() {
+            Some(last) if last.end >= start => last.end = failed.end,
+            _ => self.failed.push(start..failed.end),
+        }
+    }
+}
+
+/// Write one block, returning how much of it got written before failing
+fn write_block<O: Write>(output: &mut O, block: &[u8]) -> Result<(), usize> {
+    let mut done = 0;
+    while done < block.len() {
+        match output.write(&block[done..]) {
+            Ok(0) => return Err(done),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return Err(done),
+        }
+    }
+    Ok(())
+}
+
+/// Write data at `offset` in the image to the output. When continuing on write errors, data
+/// failing to be written is retried block by block and blocks which still fail are skipped
+fn write_output<O: Write + SeekForward>(
+    output: &mut O,
+    data: &[u8],
+    offset: u64,
+    recovery: &mut Option<Recovery>,
+) -> Result<(), CopyError> {
+    let Some(recovery) = recovery else {
+        return output.write_all(data).map_err(CopyError::WriteError);
+    };
+    let done = match write_block(output, data) {
+        Ok(()) => return Ok(()),
+        Err(done) => done,
+    };
+    for block in recovery.blocks(offset + done as u64, data.len() - done) {
+        let block = done + block.start..done + block.end;
+        if let Err(written) = write_block(output, &data[block.clone()]) {
+            output
+                .seek_forward((block.len() - written) as u64)
+                .map_err(CopyError::WriteError)?;
+            recovery.record(offset + block.start as u64..offset + block.end as u64);
+        }
+    }
+    Ok(())
+}
+
+async fn write_block_async<O: AsyncWrite + Unpin>(
+    output: &mut O,
+    block: &[u8],
+) -> Result<(), usize> {
+    let mut done = 0;
+    while done < block.len() {
+        match output.write(&block[done..]).await {
+            Ok(0) => return Err(done),
+            Ok(n) => done += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(_) => return Err(done),
+        }
+    }
+    Ok(())
+}
+
+async fn write_output_async<O: AsyncWrite + AsyncSeekForward + Unpin>(
+    output: &mut O,
+    data: &[u8],
+    offset: u64,
+    recovery: &mut Option<Recovery>,
+) -> Result<(), CopyError> {
+    let Some(recovery) = recovery else {
+        return output.write_all(data).map_err(CopyError::WriteError).await;
+    };
+    let done = match write_block_async(output, data).await {
+        Ok(()) => return Ok(()),
+        Err(done) => done,
+    };
+    for block in recovery.blocks(offset + done as u64, data.len() - done) {
+        let block = done + block.start..done + block.end;
+        if let Err(written) = write_block_async(output, &data[block.clone()]).await {
+            output
+                .async_seek_forward((block.len() - written) as u64)
+                .map_err(CopyError::WriteError)
+                .await?;
+            recovery.record(offset + block.start as u64..offset + block.end as u64);
+        }
+    }
+    Ok(())
+}
+
+fn write_zeroes<O: Write + SeekForward>(
+    output: &mut O,
+    mut offset: u64,
+    mut left: u64,
+    options: &CopyOptions,
+    recovery: &mut Option<Recovery>,
+) -> Result<(), CopyError> {
+    while left > 0 {
+        let len = left.min(ZEROES.len() as u64);
+        write_output(output, &ZEROES[0..len as usize], offset, recovery)?;
+        options.report_zeroes(len);
+        offset += len;
+        left -= len;
+    }
+    Ok(())
+}
+
+async fn write_zeroes_async<O: AsyncWrite + AsyncSeekForward + Unpin>(
+    output: &mut O,
+    mut offset: u64,
+    mut left: u64,
+    options: &CopyOptions,
+    recovery: &mut Option<Recovery>,
+) -> Result<(), CopyError> {
+    while left > 0 {
+        let len = left.min(ZEROES.len() as u64);
+        write_output_async(output, &ZEROES[0..len as usize], offset, recovery).await?;
+        options.report_zeroes(len);
+        offset += len;
+        left -= len;
+    }
+    Ok(())
+}
+
+/// Hash of the whole image as it gets copied, with everything not covered by the block map taken
+/// to be zeroes
+struct ImageHasher {
+    hasher: TimedHasher,
+    position: u64,
+}
+
+impl ImageHasher {
+    fn new() -> Self {
+        Self {
+            hasher: TimedHasher::default(),
+            position: 0,
+        }
+    }
+
+    fn update(&mut self, offset: u64, data: &[u8]) {
+        self.zeroes(offset - self.position);
+        self.hasher.update(data);
+        self.position = offset + data.len() as u64;
+    }
+
+    fn zeroes(&mut self, mut left: u64) {
+        while left > 0 {
+            let len = left.min(ZEROES.len() as u64);
+            self.hasher.update(&ZEROES[0..len as usize]);
+            left -= len;
+        }
+    }
+
+    fn finish(&mut self, image_size: u64) -> HashValue {
+        self.zeroes(image_size - self.position);
+        HashValue::Sha256(self.hasher.finalize_reset())
+    }
+}
+
+/// Read and hash input data which isn't written to the output
+/// Checksums of the ranges merged by coalescing, computed over the ranges and the data bridged
+/// between them while copying
+#[derive(Default)]
+struct Merger {
+    hasher: TimedHasher,
+    start: Option<usize>,
+    failed: bool,
+    groups: Vec<(Range<usize>, HashValue)>,
+}
+
+impl Merger {
+    /// Start copying the range at `index`, opening a group if it's bridged to the next one
+    fn enter(&mut self, index: usize, range: &BlockRange) {
+        if self.start.is_none() && range.is_bridged() {
+            self.start = Some(index);
+        }
+    }
+
+    /// Hasher for the data copied while inside a group
+    fn hasher(&mut self) -> Option<&mut TimedHasher> {
+        self.start.map(|_| &mut self.hasher)
+    }
+
+    /// Done verifying the range at `index`, closing the group unless it's bridged to the next
+    /// one. Groups with ranges failing verification don't get merged
+    fn verified(&mut self, index: usize, range: &BlockRange, ok: bool) {
+        let Some(start) = self.start else {
+            return;
+        };
+        self.failed |= !ok;
+        if range.is_bridged() {
+            return;
+        }
+        let digest = self.hasher.finalize_reset();
+        if !self.failed {
+            self.groups
+                .push((start..index + 1, HashValue::Sha256(digest)));
+        }
+        self.start = None;
+        self.failed = false;
+    }
+
+    fn finish(self, map: &Bmap) -> Option<Bmap> {
+        (!self.groups.is_empty()).then(|| map.merge(&self.groups))
+    }
+}
+
+fn hash_input<I: Read>(
+    input: &mut I,
+    hasher: &mut TimedHasher,
+    buf: &mut [u8],
+    mut left: u64,
+) -> Result<(), CopyError> {
+    while left > 0 {
+        let toread = left.min(buf.len() as u64) as usize;
+        let r = input
+            .read(&mut buf[0..toread])
+            .map_err(CopyError::ReadError)?;
+        if r == 0 {
+            return Err(CopyError::UnexpectedEof);
+        }
+        hasher.update(&buf[0..r]);
+        left -= r as u64;
+    }
+    Ok(())
+}
+
+async fn hash_input_async<I: AsyncRead + Unpin>(
+    input: &mut I,
+    hasher: &mut TimedHasher,
+    buf: &mut [u8],
+    mut left: u64,
+) -> Result<(), CopyError> {
+    while left > 0 {
+        let toread = left.min(buf.len() as u64) as usize;
+        let r = input
+            .read(&mut buf[0..toread])
+            .map_err(CopyError::ReadError)
+            .await?;
+        if r == 0 {
+            return Err(CopyError::UnexpectedEof);
+        }
+        hasher.update(&buf[0..r]);
+        left -= r as u64;
+    }
+    Ok(())
+}
+
+/// Copy input data at `offset` in the image which isn't covered by a checksum straight to the
+/// output
+fn copy_padding<I: Read, O: Write + SeekForward>(
+    input: &mut I,
+    output: &mut O,
+    buf: &mut [u8],
+    mut offset: u64,
+    mut left: u64,
+    mut hasher: Option<&mut TimedHasher>,
+    recovery: &mut Option<Recovery>,
+) -> Result<(), CopyError> {
+    while left > 0 {
+        let toread = left.min(buf.len() as u64) as usize;
+        let r = input
+            .read(&mut buf[0..toread])
+            .map_err(CopyError::ReadError)?;
+        if r == 0 {
+            return Err(CopyError::UnexpectedEof);
+        }
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&buf[0..r]);
+        }
+        write_output(output, &buf[0..r], offset, recovery)?;
+        offset += r as u64;
+        left -= r as u64;
+    }
+    Ok(())
+}
+
+async fn copy_padding_async<I, O>(
+    input: &mut I,
+    output: &mut O,
+    buf: &mut [u8],
+    mut offset: u64,
+    mut left: u64,
+    mut hasher: Option<&mut TimedHasher>,
+    recovery: &mut Option<Recovery>,
+) -> Result<(), CopyError>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite + AsyncSeekForward + Unpin,
+{
+    while left > 0 {
+        let toread = left.min(buf.len() as u64) as usize;
+        let r = input
+            .read(&mut buf[0..toread])
+            .map_err(CopyError::ReadError)
+            .await?;
+        if r == 0 {
+            return Err(CopyError::UnexpectedEof);
+        }
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&buf[0..r]);
+        }
+        write_output_async(output, &buf[0..r], offset, recovery).await?;
+        offset += r as u64;
+        left -= r as u64;
+    }
+    Ok(())
+}
+
+pub fn copy<I, O>(input: &mut I, output: &mut O, map: &Bmap) -> Result<CopyReport, CopyError>
+where
+    I: Read + SeekForward,
+    O: Write + SeekForward,
+{
+    copy_with_options(input, output, map, &CopyOptions::default())
 }
 
-pub fn copy<I, O>(input: &mut I, output: &mut O, map: &Bmap) -> Result<(), CopyError>
+pub fn copy_with_options<I, O>(
+    input: &mut I,
+    output: &mut O,
+    map: &Bmap,
+    options: &CopyOptions,
+) -> Result<CopyReport, CopyError>
 where
     I: Read + SeekForward,
     O: Write + SeekForward,
 {
+    let started = Instant::now();
+    let (mut measured_input, mut measured_output) = (Measured::new(input), Measured::new(output));
+    let mut input = RateLimited::new(&mut measured_input, options.input_bucket())
+        .counting_seeks(options.counts_input_seeks());
+    let mut output = RateLimited::new(&mut measured_output, options.output_bucket());
+    let (input, output) = (&mut input, &mut output);
     let mut hasher = match map.checksum_type() {
-        HashType::Sha256 => Sha256::new(),
+        HashType::Sha256 => TimedHasher::default(),
     };
 
     // TODO benchmark a reasonable size for this
     let mut v = vec![0; 8 * 1024 * 1024];
 
     let buf = v.as_mut_slice();
+    let end = if options.holes_zeroed() {
+        map.image_size()
+    } else {
+        map.mapped_end()
+    };
+    options.check_bounds(end)?;
+    output
+        .seek_forward(options.offset())
+        .map_err(CopyError::WriteError)?;
+
+    let mut image_hasher = options.image_digest_enabled().then(ImageHasher::new);
+    let mut recovery = options
+        .continues_on_error()
+        .then(|| Recovery::new(map.block_size()));
+    let mut checksum_errors = Vec::new();
+    let mut merger = Merger::default();
     let mut position = 0;
-    for range in map.block_map() {
-        let forward = range.offset() - position;
-        input.seek_forward(forward).map_err(CopyError::ReadError)?;
-        output
-            .seek_forward(forward)
-            .map_err(CopyError::WriteError)?;
+    let mut input_position = 0;
+    for (index, range) in map.block_map().enumerate() {
+        merger.enter(index, range);
+        let written = range.write_range();
+        let start = map.source_offset() + written.start - range.lead();
+        input
+            .seek_forward(start - input_position)
+            .map_err(CopyError::ReadError)?;
+        let forward = written.start - position;
+        if options.holes_zeroed() {
+            write_zeroes(output, position, forward, options, &mut recovery)?;
+        } else {
+            output
+                .seek_forward(forward)
+                .map_err(CopyError::WriteError)?;
+        }
 
+        copy_padding(
+            input,
+            output,
+            buf,
+            written.start,
+            range.pad_before(),
+            None,
+            &mut recovery,
+        )?;
+        hash_input(input, &mut hasher, buf, range.lead())?;
+        let mut offset = range.offset();
         let mut left = range.length() as usize;
         while left > 0 {
             let toread = left.min(buf.len());
@@ -79,48 +503,157 @@ where
                 return Err(CopyError::UnexpectedEof);
             }
             hasher.update(&buf[0..r]);
-            output
-                .write_all(&buf[0..r])
-                .map_err(CopyError::WriteError)?;
+            if let Some(merged) = merger.hasher() {
+                merged.update(&buf[0..r]);
+            }
+            if let Some(image_hasher) = &mut image_hasher {
+                image_hasher.update(offset, &buf[0..r]);
+            }
+            write_output(output, &buf[0..r], offset, &mut recovery)?;
+            offset += r as u64;
             left -= r;
         }
+        hash_input(input, &mut hasher, buf, range.trail())?;
         let digest = hasher.finalize_reset();
-        if range.checksum().as_slice() != &digest[..] {
-            return Err(CopyError::ChecksumError);
+        let verified = range.checksum().as_slice() == digest;
+        if !verified {
+            if recovery.is_none() {
+                return Err(CopyError::ChecksumError);
+            }
+            checksum_errors.push(range.offset()..offset);
         }
+        merger.verified(index, range, verified);
+        copy_padding(
+            input,
+            output,
+            buf,
+            offset,
+            range.pad_after(),
+            merger.hasher(),
+            &mut recovery,
+        )?;
 
-        position = range.offset() + range.length();
+        position = written.end;
+        input_position = start + range.lead() + (written.end - written.start) + range.trail();
     }
 
-    Ok(())
+    if options.holes_zeroed() {
+        write_zeroes(
+            output,
+            position,
+            map.image_size() - position,
+            options,
+            &mut recovery,
+        )?;
+    }
+    output.flush().map_err(CopyError::WriteError)?;
+
+    let mut report = CopyReport::measured(
+        input.get_ref().measurements(),
+        output.get_ref().measurements(),
+        options.offset(),
+    );
+    report.ranges = map.block_map().len() as u64;
+    report.hash_time = hasher.time + merger.hasher.time;
+    report.coalesced = merger.finish(map);
+    if let Some(recovery) = recovery {
+        report.write_errors = recovery.failed;
+        report.checksum_errors = checksum_errors;
+    }
+    if let Some(mut image_hasher) = image_hasher {
+        report.image_digest = Some(image_hasher.finish(map.image_size()));
+        report.hash_time += image_hasher.hasher.time;
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
 }
 
-pub async fn copy_async<I, O>(input: &mut I, output: &mut O, map: &Bmap) -> Result<(), CopyError>
+pub async fn copy_async<I, O>(
+    input: &mut I,
+    output: &mut O,
+    map: &Bmap,
+) -> Result<CopyReport, CopyError>
 where
-    I: AsyncRead + AsyncSeekForward + Unpin,
-    O: AsyncWrite + AsyncSeekForward + Unpin,
+    I: AsyncRead + AsyncSeekForward + Unpin + Send,
+    O: AsyncWrite + AsyncSeekForward + Unpin + Send,
+{
+    copy_async_with_options(input, output, map, &CopyOptions::default()).await
+}
+
+pub async fn copy_async_with_options<I, O>(
+    input: &mut I,
+    output: &mut O,
+    map: &Bmap,
+    options: &CopyOptions,
+) -> Result<CopyReport, CopyError>
+where
+    I: AsyncRead + AsyncSeekForward + Unpin + Send,
+    O: AsyncWrite + AsyncSeekForward + Unpin + Send,
 {
+    let started = Instant::now();
+    let (mut measured_input, mut measured_output) = (Measured::new(input), Measured::new(output));
+    let mut input = RateLimited::new(&mut measured_input, options.input_bucket())
+        .counting_seeks(options.counts_input_seeks());
+    let mut output = RateLimited::new(&mut measured_output, options.output_bucket());
+    let (input, output) = (&mut input, &mut output);
     let mut hasher = match map.checksum_type() {
-        HashType::Sha256 => Sha256::new(),
+        HashType::Sha256 => TimedHasher::default(),
     };
 
     // TODO benchmark a reasonable size for this
     let mut v = vec![0; 8 * 1024 * 1024];
 
     let buf = v.as_mut_slice();
+    let end = if options.holes_zeroed() {
+        map.image_size()
+    } else {
+        map.mapped_end()
+    };
+    options.check_bounds(end)?;
+    output
+        .async_seek_forward(options.offset())
+        .map_err(CopyError::WriteError)
+        .await?;
+
+    let mut image_hasher = options.image_digest_enabled().then(ImageHasher::new);
+    let mut recovery = options
+        .continues_on_error()
+        .then(|| Recovery::new(map.block_size()));
+    let mut checksum_errors = Vec::new();
+    let mut merger = Merger::default();
     let mut position = 0;
-    for range in map.block_map() {
-        let forward = range.offset() - position;
+    let mut input_position = 0;
+    for (index, range) in map.block_map().enumerate() {
+        merger.enter(index, range);
+        let written = range.write_range();
+        let start = map.source_offset() + written.start - range.lead();
         input
-            .async_seek_forward(forward)
+            .async_seek_forward(start - input_position)
             .map_err(CopyError::ReadError)
             .await?;
-        output.flush().map_err(CopyError::WriteError).await?;
-        output
-            .async_seek_forward(forward)
-            .map_err(CopyError::WriteError)
-            .await?;
+        let forward = written.start - position;
+        if options.holes_zeroed() {
+            write_zeroes_async(output, position, forward, options, &mut recovery).await?;
+        } else if forward > 0 {
+            output.flush().map_err(CopyError::WriteError).await?;
+            output
+                .async_seek_forward(forward)
+                .map_err(CopyError::WriteError)
+                .await?;
+        }
 
+        copy_padding_async(
+            input,
+            output,
+            buf,
+            written.start,
+            range.pad_before(),
+            None,
+            &mut recovery,
+        )
+        .await?;
+        hash_input_async(input, &mut hasher, buf, range.lead()).await?;
+        let mut offset = range.offset();
         let mut left = range.length() as usize;
         while left > 0 {
             let toread = left.min(buf.len());
@@ -132,20 +665,71 @@ where
                 return Err(CopyError::UnexpectedEof);
             }
             hasher.update(&buf[0..r]);
-            output
-                .write_all(&buf[0..r])
-                .await
-                .map_err(CopyError::WriteError)?;
+            if let Some(merged) = merger.hasher() {
+                merged.update(&buf[0..r]);
+            }
+            if let Some(image_hasher) = &mut image_hasher {
+                image_hasher.update(offset, &buf[0..r]);
+            }
+            write_output_async(output, &buf[0..r], offset, &mut recovery).await?;
+            offset += r as u64;
             left -= r;
         }
+        hash_input_async(input, &mut hasher, buf, range.trail()).await?;
         let digest = hasher.finalize_reset();
-        if range.checksum().as_slice() != &digest[..] {
-            return Err(CopyError::ChecksumError);
+        let verified = range.checksum().as_slice() == digest;
+        if !verified {
+            if recovery.is_none() {
+                return Err(CopyError::ChecksumError);
+            }
+            checksum_errors.push(range.offset()..offset);
         }
+        merger.verified(index, range, verified);
+        copy_padding_async(
+            input,
+            output,
+            buf,
+            offset,
+            range.pad_after(),
+            merger.hasher(),
+            &mut recovery,
+        )
+        .await?;
 
-        position = range.offset() + range.length();
+        position = written.end;
+        input_position = start + range.lead() + (written.end - written.start) + range.trail();
     }
-    Ok(())
+
+    if options.holes_zeroed() {
+        write_zeroes_async(
+            output,
+            position,
+            map.image_size() - position,
+            options,
+            &mut recovery,
+        )
+        .await?;
+    }
+    output.flush().map_err(CopyError::WriteError).await?;
+
+    let mut report = CopyReport::measured(
+        input.get_ref().measurements(),
+        output.get_ref().measurements(),
+        options.offset(),
+    );
+    report.ranges = map.block_map().len() as u64;
+    report.hash_time = hasher.time + merger.hasher.time;
+    report.coalesced = merger.finish(map);
+    if let Some(recovery) = recovery {
+        report.write_errors = recovery.failed;
+        report.checksum_errors = checksum_errors;
+    }
+    if let Some(mut image_hasher) = image_hasher {
+        report.image_digest = Some(image_hasher.finish(map.image_size()));
+        report.hash_time += image_hasher.hasher.time;
+    }
+    report.elapsed = started.elapsed();
+    Ok(report)
 }
 
 pub fn copy_nobmap<I, O>(input: &mut I, output: &mut O) -> Result<(), CopyError>
@@ -159,11 +743,164 @@ where
 
 pub async fn copy_async_nobmap<I, O>(input: &mut I, output: &mut O) -> Result<(), CopyError>
 where
-    I: AsyncRead + AsyncSeekForward + Unpin,
-    O: AsyncWrite + AsyncSeekForward + Unpin,
+    I: AsyncRead + AsyncSeekForward + Unpin + Send,
+    O: AsyncWrite + AsyncSeekForward + Unpin + Send,
 {
     futures::io::copy(input, output)
         .map_err(CopyError::WriteError)
         .await?;
     Ok(())
 }
+
+/// Read until the buffer is full or the end of the input is reached
+fn fill_buffer<I: Read>(input: &mut I, buf: &mut [u8]) -> IOResult<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(r) => filled += r,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+async fn fill_buffer_async<I: AsyncRead + Unpin>(input: &mut I, buf: &mut [u8]) -> IOResult<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]).await {
+            Ok(0) => break,
+            Ok(r) => filled += r,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Copy without a bmap. With the sparse option set all-zero blocks are skipped on the output
+/// rather than written. The returned map describes the skipped ranges, note that the output
+/// isn't extended over a trailing hole.
+pub fn copy_nobmap_with_options<I, O>(
+    input: &mut I,
+    output: &mut O,
+    options: &CopyOptions,
+) -> Result<SparseMap, CopyError>
+where
+    I: Read,
+    O: Write + SeekForward,
+{
+    let mut input = RateLimited::new(input, options.input_bucket());
+    let mut output = RateLimited::new(output, options.output_bucket());
+    let (input, output) = (&mut input, &mut output);
+    let mut v = vec![0; 8 * 1024 * 1024];
+    let buf = v.as_mut_slice();
+    let mut map = SparseMap::default();
+    let mut skip = 0;
+    output
+        .seek_forward(options.offset())
+        .map_err(CopyError::WriteError)?;
+    loop {
+        let r = fill_buffer(input, buf).map_err(CopyError::ReadError)?;
+        if r == 0 {
+            break;
+        }
+        options.check_bounds(map.image_size() + r as u64)?;
+        if !options.is_sparse() {
+            output
+                .write_all(&buf[0..r])
+                .map_err(CopyError::WriteError)?;
+            map.add_data(r as u64);
+            continue;
+        }
+
+        for (hole, range) in zero_runs(&buf[0..r]) {
+            let len = range.len() as u64;
+            if hole {
+                skip += len;
+                map.add_hole(len);
+                continue;
+            }
+            if skip > 0 {
+                output.seek_forward(skip).map_err(CopyError::WriteError)?;
+                skip = 0;
+            }
+            output
+                .write_all(&buf[range])
+                .map_err(CopyError::WriteError)?;
+            map.add_data(len);
+        }
+    }
+    output.seek_forward(skip).map_err(CopyError::WriteError)?;
+
+    Ok(map)
+}
+
+pub async fn copy_async_nobmap_with_options<I, O>(
+    input: &mut I,
+    output: &mut O,
+    options: &CopyOptions,
+) -> Result<SparseMap, CopyError>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite + AsyncSeekForward + Unpin + Send,
+{
+    let mut input = RateLimited::new(input, options.input_bucket());
+    let mut output = RateLimited::new(output, options.output_bucket());
+    let (input, output) = (&mut input, &mut output);
+    let mut v = vec![0; 8 * 1024 * 1024];
+    let buf = v.as_mut_slice();
+    let mut map = SparseMap::default();
+    let mut skip = 0;
+    output
+        .async_seek_forward(options.offset())
+        .map_err(CopyError::WriteError)
+        .await?;
+    loop {
+        let r = fill_buffer_async(input, buf)
+            .map_err(CopyError::ReadError)
+            .await?;
+        if r == 0 {
+            break;
+        }
+        options.check_bounds(map.image_size() + r as u64)?;
+        if !options.is_sparse() {
+            output
+                .write_all(&buf[0..r])
+                .map_err(CopyError::WriteError)
+                .await?;
+            map.add_data(r as u64);
+            continue;
+        }
+
+        for (hole, range) in zero_runs(&buf[0..r]) {
+            let len = range.len() as u64;
+            if hole {
+                skip += len;
+                map.add_hole(len);
+                continue;
+            }
+            if skip > 0 {
+                output.flush().map_err(CopyError::WriteError).await?;
+                output
+                    .async_seek_forward(skip)
+                    .map_err(CopyError::WriteError)
+                    .await?;
+                skip = 0;
+            }
+            output
+                .write_all(&buf[range])
+                .map_err(CopyError::WriteError)
+                .await?;
+            map.add_data(len);
+        }
+    }
+    output.flush().map_err(CopyError::WriteError).await?;
+    output
+        .async_seek_forward(skip)
+        .map_err(CopyError::WriteError)
+        .await?;
+
+    Ok(map)
+}
@@ -0,0 +1,170 @@
+use crate::{AsyncSeekForward, SeekForward};
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use sha2::{Digest, Sha256};
+use std::io::Result as IOResult;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// What went through a measured reader or writer
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Measurements {
+    /// Bytes read or written
+    pub(crate) transferred: u64,
+    /// Bytes seeked over
+    pub(crate) seeked: u64,
+    /// Time spent reading, writing or seeking
+    pub(crate) busy: Duration,
+    /// Time spent flushing
+    pub(crate) flushing: Duration,
+}
+
+/// Adaptor measuring the data passing through the inner reader or writer and the time it takes.
+/// For asynchronous I/O operations the time from first being polled until completing is counted
+pub(crate) struct Measured<'a, T: ?Sized> {
+    inner: &'a mut T,
+    measurements: Measurements,
+    started: Option<Instant>,
+}
+
+impl<'a, T: ?Sized> Measured<'a, T> {
+    pub(crate) fn new(inner: &'a mut T) -> Self {
+        Self {
+            inner,
+            measurements: Measurements::default(),
+            started: None,
+        }
+    }
+
+    pub(crate) fn measurements(&self) -> Measurements {
+        self.measurements
+    }
+
+    fn start(&mut self) {
+        self.started.get_or_insert_with(Instant::now);
+    }
+
+    fn finish(&mut self) -> Duration {
+        self.started.take().map_or(Duration::ZERO, |s| s.elapsed())
+    }
+}
+
+impl<T: Read + ?Sized> Read for Measured<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let start = Instant::now();
+        let r = self.inner.read(buf);
+        self.measurements.busy += start.elapsed();
+        let r = r?;
+        self.measurements.transferred += r as u64;
+        Ok(r)
+    }
+}
+
+impl<T: Write + ?Sized> Write for Measured<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let start = Instant::now();
+        let written = self.inner.write(buf);
+        self.measurements.busy += start.elapsed();
+        let written = written?;
+        self.measurements.transferred += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        let start = Instant::now();
+        let r = self.inner.flush();
+        self.measurements.flushing += start.elapsed();
+        r
+    }
+}
+
+impl<T: SeekForward + ?Sized> SeekForward for Measured<'_, T> {
+    fn seek_forward(&mut self, forward: u64) -> IOResult<()> {
+        let start = Instant::now();
+        self.inner.seek_forward(forward)?;
+        self.measurements.busy += start.elapsed();
+        self.measurements.seeked += forward;
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + Unpin + ?Sized> AsyncRead for Measured<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IOResult<usize>> {
+        self.start();
+        let r = ready!(Pin::new(&mut *self.inner).poll_read(cx, buf));
+        let elapsed = self.finish();
+        self.measurements.busy += elapsed;
+        let r = r?;
+        self.measurements.transferred += r as u64;
+        Poll::Ready(Ok(r))
+    }
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> AsyncWrite for Measured<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IOResult<usize>> {
+        self.start();
+        let written = ready!(Pin::new(&mut *self.inner).poll_write(cx, buf));
+        let elapsed = self.finish();
+        self.measurements.busy += elapsed;
+        let written = written?;
+        self.measurements.transferred += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        self.start();
+        let r = ready!(Pin::new(&mut *self.inner).poll_flush(cx));
+        let elapsed = self.finish();
+        self.measurements.flushing += elapsed;
+        Poll::Ready(r)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        self.start();
+        let r = ready!(Pin::new(&mut *self.inner).poll_close(cx));
+        let elapsed = self.finish();
+        self.measurements.flushing += elapsed;
+        Poll::Ready(r)
+    }
+}
+
+#[async_trait]
+impl<T: AsyncSeekForward + Send + ?Sized> AsyncSeekForward for Measured<'_, T> {
+    async fn async_seek_forward(&mut self, forward: u64) -> IOResult<()> {
+        let start = Instant::now();
+        self.inner.async_seek_forward(forward).await?;
+        self.measurements.busy += start.elapsed();
+        self.measurements.seeked += forward;
+        Ok(())
+    }
+}
+
+/// SHA-256 hasher keeping track of the time spent hashing
+#[derive(Clone, Default)]
+pub(crate) struct TimedHasher {
+    hasher: Sha256,
+    pub(crate) time: Duration,
+}
+
+impl TimedHasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        let start = Instant::now();
+        self.hasher.update(data);
+        self.time += start.elapsed();
+    }
+
+    pub(crate) fn finalize_reset(&mut self) -> [u8; 32] {
+        self.hasher.finalize_reset().into()
+    }
+}
@@ -0,0 +1,166 @@
+use crate::CopyError;
+use crate::ratelimit::TokenBucket;
+use std::fmt;
+use std::sync::Arc;
+
+type ProgressFn = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Options changing how an image gets copied
+#[derive(Clone, Default)]
+pub struct CopyOptions {
+    zero_holes: bool,
+    zero_progress: Option<ProgressFn>,
+    sparse: bool,
+    dest_offset: u64,
+    dest_size: Option<u64>,
+    image_digest: bool,
+    continue_on_error: bool,
+    input_rate: Option<u64>,
+    input_reads_seeks: bool,
+    output_rate: Option<u64>,
+}
+
+impl CopyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write zeroes over all ranges not covered by the bmap rather than skipping them, such
+    /// that the output ends up byte-identical to the full image
+    pub fn zero_holes(&mut self, zero_holes: bool) -> &mut Self {
+        self.zero_holes = zero_holes;
+        self
+    }
+
+    /// Callback called with the number of bytes of each batch of zeroes written while filling
+    /// holes. These are accounted separately from the mapped data
+    pub fn on_zero_progress<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(u64) + Send + Sync + 'static,
+    {
+        self.zero_progress = Some(Arc::new(f));
+        self
+    }
+
+    /// When copying without a bmap, seek over all-zero blocks on the output rather than writing
+    /// them. Only safe when the output is known to read back zeroes in the skipped ranges, e.g. a
+    /// freshly truncated file or a device whose holes get discarded afterwards
+    pub fn sparse(&mut self, sparse: bool) -> &mut Self {
+        self.sparse = sparse;
+        self
+    }
+
+    /// Offset in the output at which the image starts, e.g. to write an image into a partition
+    /// of a larger disk
+    pub fn dest_offset(&mut self, offset: u64) -> &mut Self {
+        self.dest_offset = offset;
+        self
+    }
+
+    /// Size of the output in bytes. Copies which would write beyond it are refused
+    pub fn dest_size(&mut self, size: u64) -> &mut Self {
+        self.dest_size = Some(size);
+        self
+    }
+
+    /// Compute a digest of the whole image while copying, with everything not covered by the
+    /// block map taken to be zeroes. It's returned in the copy report
+    pub fn image_digest(&mut self, image_digest: bool) -> &mut Self {
+        self.image_digest = image_digest;
+        self
+    }
+
+    /// Carry on past blocks failing to be written, retrying failed writes block by block, and
+    /// past ranges failing verification. Both get recorded in the copy report instead. Failed
+    /// blocks are skipped by seeking forward, so the output has to report a write failing from
+    /// the failing call itself rather than from a later one, as buffered outputs would
+    pub fn continue_on_error(&mut self, continue_on_error: bool) -> &mut Self {
+        self.continue_on_error = continue_on_error;
+        self
+    }
+
+    /// Limit reading image data from the input to `rate` bytes per second, 0 meaning no limit.
+    /// Data seeked over isn't counted unless the input reads through it, and compressed inputs
+    /// count their decoded data
+    pub fn limit_input_rate(&mut self, rate: u64) -> &mut Self {
+        self.input_rate = Some(rate).filter(|rate| *rate > 0);
+        self
+    }
+
+    /// Whether seeking the input forward reads through the data in between, e.g. for compressed,
+    /// downloaded or piped images wrapped in a [`crate::Discarder`], such that data seeked over
+    /// counts against the input rate limit as well
+    pub fn input_reads_seeks(&mut self, reads: bool) -> &mut Self {
+        self.input_reads_seeks = reads;
+        self
+    }
+
+    /// Limit writing to the output to `rate` bytes per second, 0 meaning no limit
+    pub fn limit_output_rate(&mut self, rate: u64) -> &mut Self {
+        self.output_rate = Some(rate).filter(|rate| *rate > 0);
+        self
+    }
+
+    pub(crate) fn input_bucket(&self) -> Option<TokenBucket> {
+        self.input_rate.map(TokenBucket::new)
+    }
+
+    pub(crate) fn output_bucket(&self) -> Option<TokenBucket> {
+        self.output_rate.map(TokenBucket::new)
+    }
+
+    pub(crate) fn counts_input_seeks(&self) -> bool {
+        self.input_reads_seeks
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.dest_offset
+    }
+
+    /// Check whether the output is big enough to write the image up to `end`
+    pub(crate) fn check_bounds(&self, end: u64) -> Result<(), CopyError> {
+        let end = self.dest_offset + end;
+        match self.dest_size {
+            Some(size) if end > size => Err(CopyError::OutOfBounds { end, size }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    pub(crate) fn image_digest_enabled(&self) -> bool {
+        self.image_digest
+    }
+
+    pub(crate) fn continues_on_error(&self) -> bool {
+        self.continue_on_error
+    }
+
+    pub(crate) fn holes_zeroed(&self) -> bool {
+        self.zero_holes
+    }
+
+    pub(crate) fn report_zeroes(&self, written: u64) {
+        if let Some(f) = &self.zero_progress {
+            f(written)
+        }
+    }
+}
+
+impl fmt::Debug for CopyOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CopyOptions")
+            .field("zero_holes", &self.zero_holes)
+            .field("sparse", &self.sparse)
+            .field("dest_offset", &self.dest_offset)
+            .field("dest_size", &self.dest_size)
+            .field("image_digest", &self.image_digest)
+            .field("continue_on_error", &self.continue_on_error)
+            .field("input_rate", &self.input_rate)
+            .field("input_reads_seeks", &self.input_reads_seeks)
+            .field("output_rate", &self.output_rate)
+            .finish_non_exhaustive()
+    }
+}
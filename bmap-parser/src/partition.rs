@@ -0,0 +1,339 @@
+use std::io::{Read, Seek, Write};
+use strum::Display;
+use thiserror::Error;
+mod gpt;
+mod mbr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display)]
+#[strum(serialize_all = "UPPERCASE")]
+#[non_exhaustive]
+pub enum PartitionTableType {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    number: u32,
+    offset: u64,
+    size: u64,
+    label: Option<String>,
+}
+
+impl Partition {
+    /// Partition number as used by the kernel, starting at 1
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Offset of the partition in bytes
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Size of the partition in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Partition label, only available for GPT
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PartitionError {
+    #[error("No partition table found")]
+    NoPartitionTable,
+    #[error("Partition table extends beyond the available data")]
+    Truncated,
+    #[error("Invalid GPT: {0}")]
+    InvalidGpt(&'static str),
+    #[error("Partitions don't fit on the disk")]
+    DiskTooSmall,
+    #[error("Failed to access the disk: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone, Debug)]
+pub struct PartitionTable {
+    table_type: PartitionTableType,
+    sector_size: u64,
+    partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Number of bytes at the start of an image which are enough to parse its partition table
+    pub const HEAD_SIZE: usize = 1024 * 1024;
+
+    /// Parse the partition table from the start of an image. Only primary partitions are
+    /// supported for MBR.
+    pub fn parse(head: &[u8]) -> Result<Self, PartitionError> {
+        match gpt::parse(head)? {
+            Some(table) => Ok(table),
+            None => mbr::parse(head),
+        }
+    }
+
+    pub fn table_type(&self) -> PartitionTableType {
+        self.table_type
+    }
+
+    /// Logical sector size in bytes the table was found for
+    pub fn sector_size(&self) -> u64 {
+        self.sector_size
+    }
+
+    /// Iterator over the partitions in the table
+    pub fn partitions(&self) -> impl ExactSizeIterator<Item = &Partition> {
+        self.partitions.iter()
+    }
+
+    /// Find a partition by number or, failing that, by label
+    pub fn find(&self, name: &str) -> Option<&Partition> {
+        let by_number = name
+            .parse::<u32>()
+            .ok()
+            .and_then(|n| self.partitions.iter().find(|p| p.number == n));
+        by_number.or_else(|| self.partitions.iter().find(|p| p.label() == Some(name)))
+    }
+}
+
+/// Move the backup GPT header and partition entries to the end of a disk of `disk_size` bytes,
+/// updating the primary header and protective MBR to match.
+///
+/// This is needed after writing an image to a disk larger than the image itself. Returns `false`
+/// without touching the disk if it doesn't have a GPT.
+pub fn relocate_gpt_backup<D: Read + Write + Seek>(
+    disk: &mut D,
+    disk_size: u64,
+) -> Result<bool, PartitionError> {
+    match gpt::Table::read(disk)? {
+        Some(mut table) => {
+            table.write(disk, disk_size)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Extend the partition ending last to the end of a disk of `disk_size` bytes, returning the
+/// partition with its new size or `None` if the disk has no partitions.
+///
+/// For GPT the backup header gets moved to the end of the disk as well, see
+/// [relocate_gpt_backup]. Only the partition table is changed, growing the filesystem inside the
+/// partition is left to the OS.
+pub fn grow_last_partition<D: Read + Write + Seek>(
+    disk: &mut D,
+    disk_size: u64,
+) -> Result<Option<Partition>, PartitionError> {
+    match gpt::Table::read(disk)? {
+        Some(mut table) => {
+            let partition = table.grow_last_partition(disk_size)?;
+            table.write(disk, disk_size)?;
+            Ok(partition)
+        }
+        None => mbr::grow_last_partition(disk, disk_size),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mbr(entries: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut head = vec![0; 512];
+        for (i, (part_type, start, sectors)) in entries.iter().enumerate() {
+            let entry = &mut head[446 + i * 16..446 + (i + 1) * 16];
+            entry[4] = *part_type;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+        }
+        head[510] = 0x55;
+        head[511] = 0xaa;
+        head
+    }
+
+    fn gpt(sector_size: usize, entries: &[(u64, u64, &str)]) -> Vec<u8> {
+        let mut head = mbr(&[(0xee, 1, u32::MAX)]);
+        head.resize(sector_size * 2 + 128 * 128, 0);
+
+        let array = &mut head[sector_size * 2..];
+        for (i, (first, last, label)) in entries.iter().enumerate() {
+            let entry = &mut array[i * 128..(i + 1) * 128];
+            entry[0..16].copy_from_slice(&[0xaa; 16]);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in label.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let entries_crc = crc32fast::hash(array);
+
+        let header = &mut head[sector_size..sector_size + 92];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[72..80].copy_from_slice(&2u64.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = gpt::header_crc(header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        head
+    }
+
+    #[test]
+    fn parse_mbr() {
+        let table = PartitionTable::parse(&mbr(&[(0x83, 2048, 4096), (0, 0, 0), (0x0c, 8192, 10)]))
+            .unwrap();
+        assert_eq!(PartitionTableType::Mbr, table.table_type());
+        let partitions: Vec<_> = table
+            .partitions()
+            .map(|p| (p.number(), p.offset(), p.size()))
+            .collect();
+        assert_eq!(vec![(1, 1048576, 2097152), (3, 4194304, 5120)], partitions);
+        assert_eq!(Some(4194304), table.find("3").map(Partition::offset));
+        assert!(table.find("2").is_none());
+
+        assert!(matches!(
+            PartitionTable::parse(&[0; 512]),
+            Err(PartitionError::NoPartitionTable)
+        ));
+        assert!(matches!(
+            PartitionTable::parse(&mbr(&[(0xee, 1, 100)])),
+            Err(PartitionError::InvalidGpt(_))
+        ));
+    }
+
+    #[test]
+    fn parse_gpt() {
+        for sector_size in [512, 4096] {
+            let head = gpt(sector_size, &[(34, 99, "boot"), (100, 199, "rootfs")]);
+            let table = PartitionTable::parse(&head).unwrap();
+            assert_eq!(PartitionTableType::Gpt, table.table_type());
+            assert_eq!(sector_size as u64, table.sector_size());
+
+            let rootfs = table.find("rootfs").unwrap();
+            assert_eq!(2, rootfs.number());
+            assert_eq!(Some("rootfs"), rootfs.label());
+            assert_eq!(100 * sector_size as u64, rootfs.offset());
+            assert_eq!(100 * sector_size as u64, rootfs.size());
+            assert_eq!(Some("boot"), table.find("1").and_then(Partition::label));
+
+            let mut corrupt = head.clone();
+            corrupt[sector_size * 2 + 40] ^= 1;
+            assert!(matches!(
+                PartitionTable::parse(&corrupt),
+                Err(PartitionError::InvalidGpt(_))
+            ));
+            assert!(matches!(
+                PartitionTable::parse(&head[0..sector_size * 3]),
+                Err(PartitionError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn parse_gpt_out_of_range() {
+        // Locations beyond what fits in 64 bits are refused rather than overflowing
+        let mut head = gpt(512, &[(34, 99, "boot")]);
+        head[512 + 72..512 + 80].copy_from_slice(&(u64::MAX / 256).to_le_bytes());
+        let crc = gpt::header_crc(&head[512..512 + 92]);
+        head[512 + 16..512 + 20].copy_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            PartitionTable::parse(&head),
+            Err(PartitionError::Truncated)
+        ));
+
+        for (first, last) in [(u64::MAX / 256, u64::MAX / 256), (34, u64::MAX)] {
+            assert!(matches!(
+                PartitionTable::parse(&gpt(512, &[(first, last, "boot")])),
+                Err(PartitionError::InvalidGpt(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn relocate_gpt() {
+        let u64_at = |data: &[u8], offset: usize| {
+            u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+        };
+
+        let mut head = gpt(512, &[(34, 99, "boot"), (100, 199, "rootfs")]);
+        head.resize(1024 * 1024, 0);
+        let mut disk = Cursor::new(head);
+        assert!(relocate_gpt_backup(&mut disk, 1024 * 1024).unwrap());
+
+        let disk = disk.into_inner();
+        let table = PartitionTable::parse(&disk).unwrap();
+        assert_eq!(2, table.partitions().len());
+        let primary = &disk[512..512 + 92];
+        assert_eq!(2047, u64_at(primary, 32));
+        assert_eq!(2014, u64_at(primary, 48));
+        assert_eq!(2047, u32::from_le_bytes(disk[458..462].try_into().unwrap()));
+
+        let backup = &disk[2047 * 512..2047 * 512 + 92];
+        assert_eq!(b"EFI PART", &backup[0..8]);
+        assert_eq!(gpt::header_crc(backup).to_le_bytes(), backup[16..20]);
+        assert_eq!(2047, u64_at(backup, 24));
+        assert_eq!(1, u64_at(backup, 32));
+        assert_eq!(2015, u64_at(backup, 72));
+        assert_eq!(
+            disk[1024..1024 + 128 * 128],
+            disk[2015 * 512..2015 * 512 + 128 * 128]
+        );
+
+        let mut small = Cursor::new(gpt(512, &[(34, 199, "rootfs")]));
+        assert!(matches!(
+            relocate_gpt_backup(&mut small, 200 * 512),
+            Err(PartitionError::DiskTooSmall)
+        ));
+
+        let mut mbr_disk = Cursor::new(mbr(&[(0x83, 1, 10)]));
+        assert!(!relocate_gpt_backup(&mut mbr_disk, 1024 * 1024).unwrap());
+    }
+
+    #[test]
+    fn grow_partition() {
+        let mut head = gpt(512, &[(100, 199, "rootfs"), (34, 99, "boot")]);
+        head.resize(1024 * 1024, 0);
+        let mut disk = Cursor::new(head);
+        let grown = grow_last_partition(&mut disk, 1024 * 1024)
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some("rootfs"), grown.label());
+        assert_eq!((2014 - 100 + 1) * 512, grown.size());
+
+        let disk = disk.into_inner();
+        let table = PartitionTable::parse(&disk).unwrap();
+        assert_eq!(Some(&grown), table.find("rootfs"));
+        assert_eq!(66 * 512, table.find("boot").unwrap().size());
+        assert_eq!(
+            disk[1024..1024 + 128 * 128],
+            disk[2015 * 512..2015 * 512 + 128 * 128]
+        );
+
+        let mut disk = Cursor::new(mbr(&[(0x83, 1, 10), (0x0c, 20, 10)]));
+        let grown = grow_last_partition(&mut disk, 1024 * 1024)
+            .unwrap()
+            .unwrap();
+        assert_eq!(2, grown.number());
+        assert_eq!((2048 - 20) * 512, grown.size());
+        let table = PartitionTable::parse(disk.get_ref()).unwrap();
+        assert_eq!(Some(&grown), table.find("2"));
+        assert_eq!(10 * 512, table.find("1").unwrap().size());
+
+        assert!(matches!(
+            grow_last_partition(&mut disk, 15 * 512),
+            Err(PartitionError::DiskTooSmall)
+        ));
+        let mut empty = Cursor::new(mbr(&[]));
+        assert!(
+            grow_last_partition(&mut empty, 1024 * 1024)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use super::{Partition, PartitionError, PartitionTable, PartitionTableType, mbr};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn put_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(data: &mut [u8], offset: usize, value: u64) {
+    data[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// CRC32 of a header with its own CRC field zeroed
+pub(crate) fn header_crc(header: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[0..16]);
+    hasher.update(&[0; 4]);
+    hasher.update(&header[20..]);
+    hasher.finalize()
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Header {
+    pub(crate) sector_size: u64,
+    pub(crate) entries_lba: u64,
+    pub(crate) num_entries: u32,
+    pub(crate) entry_size: u32,
+}
+
+impl Header {
+    /// Parse the primary GPT header at LBA 1 for the given sector size
+    pub(crate) fn parse(head: &[u8], sector_size: u64) -> Result<Option<Self>, PartitionError> {
+        let start = sector_size as usize;
+        if head.len() < start + MIN_HEADER_SIZE || &head[start..start + 8] != SIGNATURE {
+            return Ok(None);
+        }
+
+        let header_size = u32_at(head, start + 12) as usize;
+        if !(MIN_HEADER_SIZE..=sector_size as usize).contains(&header_size) {
+            return Err(PartitionError::InvalidGpt("Invalid header size"));
+        }
+        let header = head
+            .get(start..start + header_size)
+            .ok_or(PartitionError::Truncated)?;
+        if header_crc(header) != u32_at(header, 16) {
+            return Err(PartitionError::InvalidGpt("Header checksum mismatch"));
+        }
+
+        let entry_size = u32_at(header, 84);
+        if (entry_size as usize) < MIN_ENTRY_SIZE || entry_size % 8 != 0 {
+            return Err(PartitionError::InvalidGpt("Invalid partition entry size"));
+        }
+
+        let gpt = Header {
+            sector_size,
+            entries_lba: u64_at(header, 72),
+            num_entries: u32_at(header, 80),
+            entry_size,
+        };
+        let entries = gpt.entries(head)?;
+        if crc32fast::hash(entries) != u32_at(header, 88) {
+            return Err(PartitionError::InvalidGpt(
+                "Partition entries checksum mismatch",
+            ));
+        }
+
+        Ok(Some(gpt))
+    }
+
+    pub(crate) fn entries<'a>(&self, head: &'a [u8]) -> Result<&'a [u8], PartitionError> {
+        let start = self
+            .entries_lba
+            .checked_mul(self.sector_size)
+            .ok_or(PartitionError::Truncated)?;
+        let len = self.num_entries as u64 * self.entry_size as u64;
+        start
+            .checked_add(len)
+            .and_then(|end| usize::try_from(end).ok())
+            .and_then(|end| head.get(start as usize..end))
+            .ok_or(PartitionError::Truncated)
+    }
+}
+
+fn write_at<D: Write + Seek>(disk: &mut D, offset: u64, data: &[u8]) -> std::io::Result<()> {
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.write_all(data)
+}
+
+/// GPT read back from a disk, such that it can be modified and written out again
+pub(crate) struct Table {
+    header: Header,
+    mbr: Vec<u8>,
+    primary: Vec<u8>,
+    entries: Vec<u8>,
+}
+
+impl Table {
+    pub(crate) fn read<D: Read + Seek>(disk: &mut D) -> Result<Option<Self>, PartitionError> {
+        let mut head = Vec::new();
+        disk.seek(SeekFrom::Start(0))?;
+        disk.take(PartitionTable::HEAD_SIZE as u64)
+            .read_to_end(&mut head)?;
+
+        for sector_size in [512, 4096] {
+            if let Some(header) = Header::parse(&head, sector_size)? {
+                let start = sector_size as usize;
+                let header_size = u32_at(&head, start + 12) as usize;
+                return Ok(Some(Table {
+                    mbr: head[..mbr::SECTOR_SIZE as usize].to_vec(),
+                    primary: head[start..start + header_size].to_vec(),
+                    entries: header.entries(&head)?.to_vec(),
+                    header,
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write the table back to a disk of `disk_size` bytes, placing the backup header and
+    /// partition entries in the last sectors of the disk.
+    ///
+    /// The backup is written first, so the disk always has at least one valid table even if
+    /// writing gets interrupted.
+    pub(crate) fn write<D: Write + Seek>(
+        &mut self,
+        disk: &mut D,
+        disk_size: u64,
+    ) -> Result<(), PartitionError> {
+        let sector_size = self.header.sector_size;
+        let layout = Layout::new(self, disk_size)?;
+
+        put_u64(&mut self.primary, 32, layout.last_lba);
+        put_u64(&mut self.primary, 48, layout.last_usable);
+        put_u32(&mut self.primary, 88, crc32fast::hash(&self.entries));
+        let crc = header_crc(&self.primary);
+        put_u32(&mut self.primary, 16, crc);
+
+        let mut backup = self.primary.clone();
+        put_u64(&mut backup, 24, layout.last_lba);
+        put_u64(&mut backup, 32, 1);
+        put_u64(&mut backup, 72, layout.backup_entries_lba);
+        let crc = header_crc(&backup);
+        put_u32(&mut backup, 16, crc);
+
+        write_at(disk, layout.backup_entries_lba * sector_size, &self.entries)?;
+        write_at(disk, layout.last_lba * sector_size, &backup)?;
+        disk.flush()?;
+
+        write_at(disk, self.header.entries_lba * sector_size, &self.entries)?;
+        write_at(disk, sector_size, &self.primary)?;
+        disk.flush()?;
+
+        if mbr::set_protective_size(&mut self.mbr, layout.last_lba) {
+            write_at(disk, 0, &self.mbr)?;
+            disk.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Extend the partition ending last up to the last usable LBA of a disk of `disk_size`
+    /// bytes. Only the entries in memory are updated, the table still needs to be written.
+    pub(crate) fn grow_last_partition(
+        &mut self,
+        disk_size: u64,
+    ) -> Result<Option<Partition>, PartitionError> {
+        let layout = Layout::new(self, disk_size)?;
+        let sector_size = self.header.sector_size;
+        let last = self
+            .entries
+            .chunks_exact_mut(self.header.entry_size as usize)
+            .enumerate()
+            .filter(|(_, entry)| entry[0..16] != [0; 16])
+            .max_by_key(|(_, entry)| u64_at(entry, 40));
+        last.map(|(i, entry)| {
+            put_u64(entry, 40, layout.last_usable);
+            parse_entry(i, entry, sector_size)
+        })
+        .transpose()
+    }
+
+    /// Highest LBA used by any partition
+    fn last_used_lba(&self) -> u64 {
+        self.entries
+            .chunks_exact(self.header.entry_size as usize)
+            .filter(|entry| entry[0..16] != [0; 16])
+            .map(|entry| u64_at(entry, 40))
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Location of the backup table at the end of a disk
+struct Layout {
+    last_lba: u64,
+    backup_entries_lba: u64,
+    last_usable: u64,
+}
+
+impl Layout {
+    fn new(table: &Table, disk_size: u64) -> Result<Self, PartitionError> {
+        let sector_size = table.header.sector_size;
+        let entries_sectors = (table.entries.len() as u64).div_ceil(sector_size);
+        let first_usable = u64_at(&table.primary, 40);
+
+        let last_lba = (disk_size / sector_size)
+            .checked_sub(1)
+            .ok_or(PartitionError::DiskTooSmall)?;
+        let backup_entries_lba = last_lba
+            .checked_sub(entries_sectors)
+            .ok_or(PartitionError::DiskTooSmall)?;
+        let last_usable = backup_entries_lba
+            .checked_sub(1)
+            .ok_or(PartitionError::DiskTooSmall)?;
+        if last_usable < first_usable.max(table.last_used_lba()) {
+            return Err(PartitionError::DiskTooSmall);
+        }
+
+        Ok(Layout {
+            last_lba,
+            backup_entries_lba,
+            last_usable,
+        })
+    }
+}
+
+fn parse_label(name: &[u8]) -> Option<String> {
+    let chars: Vec<u16> = name
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|&c| c != 0)
+        .collect();
+    if chars.is_empty() {
+        None
+    } else {
+        Some(String::from_utf16_lossy(&chars))
+    }
+}
+
+fn parse_entry(index: usize, entry: &[u8], sector_size: u64) -> Result<Partition, PartitionError> {
+    let out_of_range = || PartitionError::InvalidGpt("Partition out of range");
+    let first = u64_at(entry, 32);
+    let last = u64_at(entry, 40);
+    let offset = first.checked_mul(sector_size).ok_or_else(out_of_range)?;
+    let size = last
+        .checked_add(1)
+        .and_then(|end| end.saturating_sub(first).checked_mul(sector_size))
+        .ok_or_else(out_of_range)?;
+    Ok(Partition {
+        number: index as u32 + 1,
+        offset,
+        size,
+        label: parse_label(&entry[56..128]),
+    })
+}
+
+pub(crate) fn parse(head: &[u8]) -> Result<Option<PartitionTable>, PartitionError> {
+    let mut header = None;
+    for sector_size in [512, 4096] {
+        header = Header::parse(head, sector_size)?;
+        if header.is_some() {
+            break;
+        }
+    }
+    let Some(header) = header else {
+        return Ok(None);
+    };
+
+    let partitions = header
+        .entries(head)?
+        .chunks_exact(header.entry_size as usize)
+        .enumerate()
+        .filter(|(_, entry)| entry[0..16] != [0; 16])
+        .map(|(i, entry)| parse_entry(i, entry, header.sector_size))
+        .collect::<Result<_, _>>()?;
+
+    Ok(Some(PartitionTable {
+        table_type: PartitionTableType::Gpt,
+        sector_size: header.sector_size,
+        partitions,
+    }))
+}
@@ -0,0 +1,91 @@
+use super::{Partition, PartitionError, PartitionTable, PartitionTableType};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+pub(crate) const SECTOR_SIZE: u64 = 512;
+const ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+pub(crate) const TYPE_PROTECTIVE: u8 = 0xee;
+
+pub(crate) fn parse(head: &[u8]) -> Result<PartitionTable, PartitionError> {
+    if head.len() < SECTOR_SIZE as usize || head[510..512] != [0x55, 0xaa] {
+        return Err(PartitionError::NoPartitionTable);
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in head[ENTRIES_OFFSET..ENTRIES_OFFSET + 4 * ENTRY_SIZE]
+        .chunks_exact(ENTRY_SIZE)
+        .enumerate()
+    {
+        let part_type = entry[4];
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap()) as u64;
+        if part_type == 0 || sectors == 0 {
+            continue;
+        }
+        if part_type == TYPE_PROTECTIVE {
+            return Err(PartitionError::InvalidGpt(
+                "Protective MBR without GPT header",
+            ));
+        }
+        partitions.push(Partition {
+            number: i as u32 + 1,
+            offset: start * SECTOR_SIZE,
+            size: sectors * SECTOR_SIZE,
+            label: None,
+        });
+    }
+
+    Ok(PartitionTable {
+        table_type: PartitionTableType::Mbr,
+        sector_size: SECTOR_SIZE,
+        partitions,
+    })
+}
+
+/// Update the size of the protective partition of a GPT disk whose last sector is `last_lba`,
+/// returning whether the MBR has a protective partition at all
+pub(crate) fn set_protective_size(mbr: &mut [u8], last_lba: u64) -> bool {
+    let Some(entry) = mbr[ENTRIES_OFFSET..ENTRIES_OFFSET + 4 * ENTRY_SIZE]
+        .chunks_exact_mut(ENTRY_SIZE)
+        .find(|entry| entry[4] == TYPE_PROTECTIVE)
+    else {
+        return false;
+    };
+    // The protective partition starts at LBA 1 and is capped at the largest 32 bit size
+    let sectors = last_lba.min(u32::MAX as u64) as u32;
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    true
+}
+
+/// Extend the primary partition ending last to the end of a disk of `disk_size` bytes
+pub(crate) fn grow_last_partition<D: Read + Write + Seek>(
+    disk: &mut D,
+    disk_size: u64,
+) -> Result<Option<Partition>, PartitionError> {
+    let mut mbr = vec![0; SECTOR_SIZE as usize];
+    disk.seek(SeekFrom::Start(0))?;
+    disk.read_exact(&mut mbr)?;
+
+    let table = parse(&mbr)?;
+    let Some(last) = table.partitions.iter().max_by_key(|p| p.offset + p.size) else {
+        return Ok(None);
+    };
+    if disk_size < last.offset + last.size {
+        return Err(PartitionError::DiskTooSmall);
+    }
+    let start = last.offset / SECTOR_SIZE;
+    let sectors = (disk_size / SECTOR_SIZE - start).min(u32::MAX as u64);
+
+    let index = last.number as usize - 1;
+    let entry = &mut mbr[ENTRIES_OFFSET + index * ENTRY_SIZE..][..ENTRY_SIZE];
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+    // The whole MBR fits in a single sector, so it gets updated in one go
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&mbr)?;
+    disk.flush()?;
+
+    Ok(Some(Partition {
+        size: sectors * SECTOR_SIZE,
+        ..last.clone()
+    }))
+}
@@ -0,0 +1,242 @@
+use crate::{AsyncSeekForward, SeekForward};
+use async_trait::async_trait;
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::ready;
+use futures_timer::Delay;
+use std::future::Future;
+use std::io::Result as IOResult;
+use std::io::{Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Token bucket allowing a number of bytes per second, with bursts of up to a tenth of a second
+/// worth of bytes
+#[derive(Clone, Debug)]
+pub(crate) struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    fn burst(&self) -> usize {
+        (self.rate / 10).max(1) as usize
+    }
+
+    /// Take tokens for `len` bytes, returning how long to wait before the next transfer to stay
+    /// within the rate
+    fn take(&mut self, len: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.burst() as f64);
+        self.tokens -= len as f64;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate as f64))
+    }
+}
+
+/// Adaptor limiting the rate of the data read from or written to the inner reader or writer.
+/// Seeking is only limited when counting seeks
+pub(crate) struct RateLimited<'a, T: ?Sized> {
+    inner: &'a mut T,
+    bucket: Option<TokenBucket>,
+    delay: Option<Delay>,
+    seeks: bool,
+}
+
+impl<'a, T: ?Sized> RateLimited<'a, T> {
+    pub(crate) fn new(inner: &'a mut T, bucket: Option<TokenBucket>) -> Self {
+        Self {
+            inner,
+            bucket,
+            delay: None,
+            seeks: false,
+        }
+    }
+
+    /// Count the data seeked over as transferred too, for inputs which read through it to get
+    /// ahead, e.g. downloads and compressed images
+    pub(crate) fn counting_seeks(mut self, seeks: bool) -> Self {
+        self.seeks = seeks;
+        self
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        self.inner
+    }
+
+    /// Length of the next transfer, keeping transfers within a single burst
+    fn limit(&self, len: usize) -> usize {
+        match &self.bucket {
+            Some(bucket) => len.min(bucket.burst()),
+            None => len,
+        }
+    }
+
+    fn transferred(&mut self, len: usize) -> Option<Duration> {
+        self.bucket.as_mut().and_then(|bucket| bucket.take(len))
+    }
+
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(Pin::new(delay).poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<T: Read + ?Sized> Read for RateLimited<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> IOResult<usize> {
+        let len = self.limit(buf.len());
+        let r = self.inner.read(&mut buf[0..len])?;
+        if let Some(wait) = self.transferred(r) {
+            std::thread::sleep(wait);
+        }
+        Ok(r)
+    }
+}
+
+impl<T: Write + ?Sized> Write for RateLimited<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> IOResult<usize> {
+        let len = self.limit(buf.len());
+        let written = self.inner.write(&buf[0..len])?;
+        if let Some(wait) = self.transferred(written) {
+            std::thread::sleep(wait);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: SeekForward + ?Sized> SeekForward for RateLimited<'_, T> {
+    fn seek_forward(&mut self, mut forward: u64) -> IOResult<()> {
+        if !self.seeks {
+            return self.inner.seek_forward(forward);
+        }
+        while forward > 0 {
+            let len = self.limit(forward.min(usize::MAX as u64) as usize);
+            self.inner.seek_forward(len as u64)?;
+            if let Some(wait) = self.transferred(len) {
+                std::thread::sleep(wait);
+            }
+            forward -= len as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + Unpin + ?Sized> AsyncRead for RateLimited<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IOResult<usize>> {
+        ready!(self.poll_delay(cx));
+        let len = self.limit(buf.len());
+        let r = ready!(Pin::new(&mut *self.inner).poll_read(cx, &mut buf[0..len]))?;
+        self.delay = self.transferred(r).map(Delay::new);
+        Poll::Ready(Ok(r))
+    }
+}
+
+impl<T: AsyncWrite + Unpin + ?Sized> AsyncWrite for RateLimited<'_, T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IOResult<usize>> {
+        ready!(self.poll_delay(cx));
+        let len = self.limit(buf.len());
+        let written = ready!(Pin::new(&mut *self.inner).poll_write(cx, &buf[0..len]))?;
+        self.delay = self.transferred(written).map(Delay::new);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IOResult<()>> {
+        Pin::new(&mut *self.inner).poll_close(cx)
+    }
+}
+
+#[async_trait]
+impl<T: AsyncSeekForward + Send + ?Sized> AsyncSeekForward for RateLimited<'_, T> {
+    async fn async_seek_forward(&mut self, mut forward: u64) -> IOResult<()> {
+        if !self.seeks {
+            return self.inner.async_seek_forward(forward).await;
+        }
+        if let Some(delay) = self.delay.take() {
+            delay.await;
+        }
+        while forward > 0 {
+            let len = self.limit(forward.min(usize::MAX as u64) as usize);
+            self.inner.async_seek_forward(len as u64).await?;
+            if let Some(wait) = self.transferred(len) {
+                Delay::new(wait).await;
+            }
+            forward -= len as u64;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(100, bucket.burst());
+        let wait = bucket.take(500).unwrap();
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+
+        // Tokens don't accumulate beyond a burst while idle
+        let mut bucket = TokenBucket::new(1000);
+        bucket.updated -= Duration::from_secs(10);
+        assert_eq!(None, bucket.take(100));
+        assert!(bucket.take(100).is_some());
+    }
+
+    #[test]
+    fn rate_limited() {
+        let data = vec![1; 3000];
+        let mut input = data.as_slice();
+        let mut limited = RateLimited::new(&mut input, Some(TokenBucket::new(10000)));
+        let mut output = Vec::new();
+        let start = Instant::now();
+        std::io::copy(&mut limited, &mut output).unwrap();
+        assert_eq!(data, output);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn rate_limited_seeks() {
+        let mut input = std::io::Cursor::new(vec![1; 3000]);
+        let mut limited = RateLimited::new(&mut input, Some(TokenBucket::new(10000)));
+        let start = Instant::now();
+        limited.seek_forward(3000).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        let mut limited = limited.counting_seeks(true);
+        let start = Instant::now();
+        limited.seek_forward(3000).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(6000, input.position());
+    }
+}
@@ -0,0 +1,133 @@
+use crate::measure::Measurements;
+use crate::{Bmap, HashValue};
+use std::ops::Range;
+use std::time::Duration;
+
+/// Outcome of copying an image with a bmap
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    pub(crate) image_digest: Option<HashValue>,
+    pub(crate) coalesced: Option<Bmap>,
+    pub(crate) write_errors: Vec<Range<u64>>,
+    pub(crate) checksum_errors: Vec<Range<u64>>,
+    pub(crate) ranges: u64,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_discarded: u64,
+    pub(crate) bytes_written: u64,
+    pub(crate) bytes_skipped: u64,
+    pub(crate) decode_time: Duration,
+    pub(crate) hash_time: Duration,
+    pub(crate) write_time: Duration,
+    pub(crate) sync_time: Duration,
+    pub(crate) elapsed: Duration,
+}
+
+impl CopyReport {
+    pub(crate) fn measured(input: Measurements, output: Measurements, dest_offset: u64) -> Self {
+        Self {
+            bytes_read: input.transferred,
+            bytes_discarded: input.seeked,
+            bytes_written: output.transferred,
+            // Getting to the start of the image on the output isn't skipping any of it
+            bytes_skipped: output.seeked - dest_offset,
+            decode_time: input.busy,
+            write_time: output.busy,
+            sync_time: output.flushing,
+            ..Default::default()
+        }
+    }
+
+    /// Digest of the whole image with everything not covered by the block map taken to be
+    /// zeroes, if asked for in the copy options
+    pub fn image_digest(&self) -> Option<HashValue> {
+        self.image_digest
+    }
+
+    /// Block map with the ranges coalesced by [`Bmap::coalesce`] merged into single ranges,
+    /// covered by checksums computed over the bridged data as well while copying. Ranges failing
+    /// verification are left unmerged
+    pub fn coalesced(&self) -> Option<&Bmap> {
+        self.coalesced.as_ref()
+    }
+
+    /// Byte ranges of the image which couldn't be written when continuing on errors
+    pub fn write_errors(&self) -> &[Range<u64>] {
+        &self.write_errors
+    }
+
+    /// Byte ranges of the image which were written but failed verification when continuing on
+    /// errors
+    pub fn checksum_errors(&self) -> &[Range<u64>] {
+        &self.checksum_errors
+    }
+
+    /// Whether any errors were skipped over
+    pub fn has_errors(&self) -> bool {
+        !self.write_errors.is_empty() || !self.checksum_errors.is_empty()
+    }
+
+    /// Number of ranges of the block map copied
+    pub fn ranges(&self) -> u64 {
+        self.ranges
+    }
+
+    /// Bytes read from the input
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Bytes of the input skipped over without being used, e.g. decoded and thrown away for
+    /// compressed inputs
+    pub fn bytes_discarded(&self) -> u64 {
+        self.bytes_discarded
+    }
+
+    /// Bytes written to the output, including zeroes written over holes
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Bytes of the output seeked over rather than written
+    pub fn bytes_skipped(&self) -> u64 {
+        self.bytes_skipped
+    }
+
+    /// Time spent reading and decoding the input
+    pub fn decode_time(&self) -> Duration {
+        self.decode_time
+    }
+
+    /// Time spent hashing the data to verify it
+    pub fn hash_time(&self) -> Duration {
+        self.hash_time
+    }
+
+    /// Time spent writing and seeking the output
+    pub fn write_time(&self) -> Duration {
+        self.write_time
+    }
+
+    /// Time spent flushing and syncing the output
+    pub fn sync_time(&self) -> Duration {
+        self.sync_time
+    }
+
+    /// Total time the copy took
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Average number of bytes written per second
+    pub fn throughput(&self) -> u64 {
+        match self.elapsed.as_secs_f64() {
+            0.0 => 0,
+            secs => (self.bytes_written as f64 / secs) as u64,
+        }
+    }
+
+    /// Account for syncing the output after the copy, for callers doing so themselves
+    pub fn add_sync_time(&mut self, time: Duration) {
+        self.sync_time += time;
+        self.elapsed += time;
+    }
+}
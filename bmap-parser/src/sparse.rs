@@ -0,0 +1,96 @@
+use std::ops::Range;
+
+/// Granularity at which all-zero data is detected when copying without a bmap
+pub const SPARSE_BLOCK_SIZE: usize = 4096;
+
+/// Layout of an image as discovered while copying it without a bmap
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SparseMap {
+    image_size: u64,
+    holes: Vec<Range<u64>>,
+}
+
+impl SparseMap {
+    /// Image size in bytes
+    pub fn image_size(&self) -> u64 {
+        self.image_size
+    }
+
+    /// Number of bytes actually written to the output
+    pub fn mapped_size(&self) -> u64 {
+        self.image_size - self.holes.iter().map(|h| h.end - h.start).sum::<u64>()
+    }
+
+    /// Iterator over the all-zero ranges that were skipped on the output
+    pub fn holes(&self) -> impl ExactSizeIterator<Item = &Range<u64>> {
+        self.holes.iter()
+    }
+
+    pub(crate) fn add_data(&mut self, len: u64) {
+        self.image_size += len;
+    }
+
+    pub(crate) fn add_hole(&mut self, len: u64) {
+        let start = self.image_size;
+        self.image_size += len;
+        match self.holes.last_mut() {
+            Some(last) if last.end == start => last.end = self.image_size,
+            _ => self.holes.push(start..self.image_size),
+        }
+    }
+}
+
+/// Split a buffer into alternating runs of data and all-zero blocks; returning whether each
+/// run is a hole along with its range in the buffer
+pub(crate) fn zero_runs(buf: &[u8]) -> impl Iterator<Item = (bool, Range<usize>)> + '_ {
+    let mut blocks = buf.chunks(SPARSE_BLOCK_SIZE).peekable();
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let first = blocks.next()?;
+        let hole = first.iter().all(|&b| b == 0);
+        let start = offset;
+        offset += first.len();
+        while let Some(block) = blocks.next_if(|b| b.iter().all(|&b| b == 0) == hole) {
+            offset += block.len();
+        }
+        Some((hole, start..offset))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs() {
+        let mut buf = vec![0; 5 * SPARSE_BLOCK_SIZE + 10];
+        buf[SPARSE_BLOCK_SIZE] = 1;
+        buf[2 * SPARSE_BLOCK_SIZE + 1] = 1;
+        buf[5 * SPARSE_BLOCK_SIZE + 9] = 1;
+
+        let bs = SPARSE_BLOCK_SIZE;
+        assert_eq!(
+            vec![
+                (true, 0..bs),
+                (false, bs..3 * bs),
+                (true, 3 * bs..5 * bs),
+                (false, 5 * bs..5 * bs + 10)
+            ],
+            zero_runs(&buf).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn map() {
+        let mut map = SparseMap::default();
+        map.add_hole(10);
+        map.add_data(5);
+        map.add_hole(5);
+        map.add_hole(5);
+        map.add_data(5);
+
+        assert_eq!(30, map.image_size());
+        assert_eq!(10, map.mapped_size());
+        assert_eq!(vec![&(0..10), &(15..25)], map.holes().collect::<Vec<_>>());
+    }
+}
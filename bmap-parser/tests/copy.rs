@@ -1,11 +1,15 @@
-use bmap_parser::{Bmap, Discarder, SeekForward};
+use bmap_parser::{Bmap, CopyOptions, Discarder, FanOut, HashType, HashValue, SeekForward};
 use flate2::read::GzDecoder;
 use sha2::{Digest, Sha256};
 use std::env;
 use std::fs::File;
 use std::io::Result as IOResult;
-use std::io::{Error, ErrorKind, Read, Write};
+use std::io::{Cursor, Error, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug)]
 struct OutputMockRange {
@@ -88,7 +92,7 @@ impl Write for OutputMock {
             _ => self.add_range(self.offset),
         };
         if range.offset as usize + range.data.len() + data.len() > maxsize {
-            return Err(Error::new(ErrorKind::Other, "Writing outside of space"));
+            return Err(Error::other("Writing outside of space"));
         }
         range.write(data);
         Ok(data.len())
@@ -110,6 +114,56 @@ impl SeekForward for OutputMock {
     }
 }
 
+/// Output failing all writes to a range of bad bytes
+struct BadOutput {
+    inner: Cursor<Vec<u8>>,
+    bad: Range<u64>,
+}
+
+impl Write for BadOutput {
+    fn write(&mut self, data: &[u8]) -> IOResult<usize> {
+        let pos = self.inner.position();
+        if self.bad.contains(&pos) {
+            return Err(Error::other("Bad block"));
+        }
+        let len = if pos < self.bad.start {
+            data.len().min((self.bad.start - pos) as usize)
+        } else {
+            data.len()
+        };
+        self.inner.write(&data[0..len])
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+impl Seek for BadOutput {
+    fn seek(&mut self, pos: SeekFrom) -> IOResult<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Output which can't seek at all, like a pipe
+struct PipeOutput(Vec<u8>);
+
+impl Write for PipeOutput {
+    fn write(&mut self, data: &[u8]) -> IOResult<usize> {
+        self.0.write(data)
+    }
+
+    fn flush(&mut self) -> IOResult<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PipeOutput {
+    fn seek(&mut self, _pos: SeekFrom) -> IOResult<u64> {
+        Err(Error::other("Illegal seek"))
+    }
+}
+
 fn setup_data(basename: &str) -> (Bmap, impl Read + SeekForward) {
     let mut datadir = PathBuf::new();
     datadir.push(env::var("CARGO_MANIFEST_DIR").unwrap());
@@ -134,6 +188,31 @@ fn setup_data(basename: &str) -> (Bmap, impl Read + SeekForward) {
     (bmap, gz)
 }
 
+/// Generate a 64 block image with a few mapped ranges filled with a pattern
+fn generate_data() -> (Bmap, Vec<u8>) {
+    const BLOCK_SIZE: u64 = 4096;
+    let ranges = [(0, 0), (4, 7), (20, 20), (40, 45)];
+    let mut data = vec![0; 64 * BLOCK_SIZE as usize];
+    let mut builder = Bmap::builder();
+    builder
+        .image_size(data.len() as u64)
+        .block_size(BLOCK_SIZE)
+        .blocks(64)
+        .mapped_blocks(ranges.iter().map(|(s, e)| e - s + 1).sum())
+        .checksum_type(HashType::Sha256);
+
+    for (start, end) in ranges {
+        let range = (start * BLOCK_SIZE) as usize..((end + 1) * BLOCK_SIZE) as usize;
+        for (i, byte) in data[range.clone()].iter_mut().enumerate() {
+            *byte = (start as usize + i % 251) as u8 | 1;
+        }
+        let checksum = Sha256::digest(&data[range]).into();
+        builder.add_block_range(start, end, HashValue::Sha256(checksum));
+    }
+
+    (builder.build().unwrap(), data)
+}
+
 fn sha256_reader<R: Read>(mut reader: R) -> [u8; 32] {
     let mut buffer = [0; 4096];
     let mut hasher = Sha256::new();
@@ -168,3 +247,518 @@ fn copy() {
     // Assert that the full gzipped content match the written output
     assert_eq!(sha256_reader(&mut input), output.sha256())
 }
+
+#[test]
+fn copy_zero_holes() {
+    let (bmap, data) = generate_data();
+    let zeroed = Arc::new(AtomicU64::new(0));
+    let mut options = CopyOptions::new();
+    options.zero_holes(true).on_zero_progress({
+        let zeroed = zeroed.clone();
+        move |len| {
+            zeroed.fetch_add(len, Ordering::Relaxed);
+        }
+    });
+
+    let mut output = Cursor::new(vec![0xff; data.len()]);
+    bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options).unwrap();
+    assert_eq!(data, output.into_inner());
+    let unmapped: u64 = bmap.unmapped_ranges().map(|r| r.end - r.start).sum();
+    assert_eq!(unmapped, zeroed.load(Ordering::Relaxed));
+
+    let mut output = futures::io::Cursor::new(vec![0xff; data.len()]);
+    futures::executor::block_on(bmap_parser::copy_async_with_options(
+        &mut futures::io::Cursor::new(&data),
+        &mut output,
+        &bmap,
+        &options,
+    ))
+    .unwrap();
+    assert_eq!(data, output.into_inner());
+    assert_eq!(2 * unmapped, zeroed.load(Ordering::Relaxed));
+
+    // With holes zeroed nothing is seeked over, so the output doesn't need to support seeking
+    let mut output = PipeOutput(Vec::new());
+    bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options).unwrap();
+    assert_eq!(data, output.0);
+}
+
+#[test]
+fn copy_nobmap_sparse() {
+    let (bmap, data) = generate_data();
+    let mut options = CopyOptions::new();
+    options.sparse(true);
+
+    let mut output = OutputMock::new(bmap.image_size());
+    let map =
+        bmap_parser::copy_nobmap_with_options(&mut data.as_slice(), &mut output, &options).unwrap();
+    assert_eq!(bmap.image_size(), map.image_size());
+    assert_eq!(bmap.total_mapped_size(), map.mapped_size());
+    assert_eq!(
+        bmap.unmapped_ranges().collect::<Vec<_>>(),
+        map.holes().cloned().collect::<Vec<_>>()
+    );
+    assert_eq!(bmap.block_map().len(), output.ranges.len());
+    assert_eq!(sha256_reader(data.as_slice()), output.sha256());
+
+    let mut output = futures::io::Cursor::new(Vec::new());
+    let map = futures::executor::block_on(bmap_parser::copy_async_nobmap_with_options(
+        &mut data.as_slice(),
+        &mut output,
+        &options,
+    ))
+    .unwrap();
+    assert_eq!(bmap.total_mapped_size(), map.mapped_size());
+    let mut written = output.into_inner();
+    written.resize(data.len(), 0);
+    assert_eq!(data, written);
+}
+
+#[test]
+fn copy_fanout() {
+    let (bmap, data) = generate_data();
+
+    // The last output is too small to hold the image, which shouldn't affect the others
+    let outputs = [
+        OutputMock::new(bmap.image_size()),
+        OutputMock::new(bmap.image_size()),
+        OutputMock::new(bmap.image_size() / 2),
+    ];
+    let mut fanout = FanOut::new(outputs);
+    bmap_parser::copy(&mut Cursor::new(&data), &mut fanout, &bmap).unwrap();
+
+    let results = fanout.finish();
+    assert_eq!(3, results.len());
+    for (output, result) in results.iter().take(2) {
+        result.as_ref().unwrap();
+        assert_eq!(sha256_reader(data.as_slice()), output.clone().sha256());
+    }
+
+    let (_, result) = &results[2];
+    assert!(result.is_err());
+}
+
+#[test]
+fn copy_dest_offset() {
+    let (bmap, data) = generate_data();
+    let offset = 3 * 4096;
+    let mut options = CopyOptions::new();
+    options
+        .zero_holes(true)
+        .dest_offset(offset)
+        .dest_size(offset + bmap.image_size());
+
+    let mut output = Cursor::new(vec![0xff; (offset + bmap.image_size()) as usize]);
+    bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options).unwrap();
+    let output = output.into_inner();
+    assert!(output[0..offset as usize].iter().all(|&b| b == 0xff));
+    assert_eq!(data, output[offset as usize..]);
+
+    let mut output = Cursor::new(Vec::new());
+    options.dest_size(offset + bmap.image_size() - 1);
+    assert!(matches!(
+        bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options),
+        Err(bmap_parser::CopyError::OutOfBounds { .. })
+    ));
+    assert!(output.get_ref().is_empty());
+
+    // Without zeroing holes only the mapped data has to fit
+    options
+        .zero_holes(false)
+        .dest_size(offset + bmap.mapped_end());
+    bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options).unwrap();
+
+    let mut output = Cursor::new(Vec::new());
+    assert!(matches!(
+        bmap_parser::copy_nobmap_with_options(&mut data.as_slice(), &mut output, &options),
+        Err(bmap_parser::CopyError::OutOfBounds { .. })
+    ));
+}
+
+#[test]
+fn copy_restricted() {
+    let (bmap, mut data) = generate_data();
+
+    // Window starting in the middle of the 4-7 range and ending in the middle of 40-45
+    let (offset, length) = (6 * 4096 + 100, 36 * 4096);
+    let restricted = bmap.restrict(offset, length);
+    assert_eq!(length, restricted.image_size());
+    assert_eq!(offset, restricted.source_offset());
+    let ranges: Vec<_> = restricted
+        .block_map()
+        .map(|r| (r.offset(), r.length(), r.is_clipped()))
+        .collect();
+    assert_eq!(
+        vec![
+            (0, 2 * 4096 - 100, true),
+            (14 * 4096 - 100, 4096, false),
+            (34 * 4096 - 100, 2 * 4096 + 100, true)
+        ],
+        ranges
+    );
+
+    let window = offset as usize..(offset + length) as usize;
+    let mut output = Cursor::new(vec![0xff; length as usize]);
+    let mut options = CopyOptions::new();
+    options.zero_holes(true);
+    bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &restricted, &options)
+        .unwrap();
+    assert_eq!(data[window.clone()], output.into_inner());
+
+    let mut output = futures::io::Cursor::new(vec![0xff; length as usize]);
+    futures::executor::block_on(bmap_parser::copy_async_with_options(
+        &mut futures::io::Cursor::new(&data),
+        &mut output,
+        &restricted,
+        &options,
+    ))
+    .unwrap();
+    assert_eq!(data[window], output.into_inner());
+
+    // Clipped ranges are verified over their full original content
+    data[4 * 4096] ^= 1;
+    assert!(matches!(
+        bmap_parser::copy(
+            &mut Cursor::new(&data),
+            &mut Cursor::new(Vec::new()),
+            &restricted
+        ),
+        Err(bmap_parser::CopyError::ChecksumError)
+    ));
+}
+
+#[test]
+fn copy_aligned() {
+    let (bmap, mut data) = generate_data();
+    // Unmapped data which ends up in the padding
+    for range in bmap.unmapped_ranges() {
+        data[range.start as usize..range.end as usize].fill(0x5a);
+    }
+
+    // Align to 8 blocks with the image written one block into the output
+    let origin = 4096;
+    let aligned = bmap.align_writes(8 * 4096, origin);
+    let written: Vec<_> = aligned.block_map().map(|r| r.write_range()).collect();
+    assert_eq!(
+        vec![
+            0..4 * 4096,
+            4 * 4096..20 * 4096,
+            20 * 4096..23 * 4096,
+            39 * 4096..47 * 4096
+        ],
+        written
+    );
+    assert_eq!(31, aligned.mapped_blocks());
+    assert_eq!(47 * 4096, aligned.mapped_end());
+    assert_eq!(
+        vec![23 * 4096..39 * 4096, 47 * 4096..64 * 4096],
+        aligned.unmapped_ranges().collect::<Vec<_>>()
+    );
+
+    let mut expected = vec![0xff; origin as usize + data.len()];
+    for range in written {
+        let (start, end) = (range.start as usize, range.end as usize);
+        expected[origin as usize + start..origin as usize + end].copy_from_slice(&data[start..end]);
+    }
+    let mut options = CopyOptions::new();
+    options.dest_offset(origin);
+
+    let mut output = Cursor::new(vec![0xff; expected.len()]);
+    bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &aligned, &options)
+        .unwrap();
+    assert_eq!(expected, output.into_inner());
+
+    let mut output = futures::io::Cursor::new(vec![0xff; expected.len()]);
+    futures::executor::block_on(bmap_parser::copy_async_with_options(
+        &mut futures::io::Cursor::new(&data),
+        &mut output,
+        &aligned,
+        &options,
+    ))
+    .unwrap();
+    assert_eq!(expected, output.into_inner());
+
+    // Only the original ranges are verified
+    data[2 * 4096] ^= 1;
+    bmap_parser::copy(
+        &mut Cursor::new(&data),
+        &mut Cursor::new(Vec::new()),
+        &aligned,
+    )
+    .unwrap();
+    data[5 * 4096] ^= 1;
+    assert!(matches!(
+        bmap_parser::copy(
+            &mut Cursor::new(&data),
+            &mut Cursor::new(Vec::new()),
+            &aligned
+        ),
+        Err(bmap_parser::CopyError::ChecksumError)
+    ));
+}
+
+#[test]
+fn copy_coalesced() {
+    let (bmap, mut data) = generate_data();
+    for range in bmap.unmapped_ranges() {
+        data[range.start as usize..range.end as usize].fill(0x5a);
+    }
+
+    let coalesced = bmap.coalesce(16 * 4096);
+    let written: Vec<_> = coalesced.block_map().map(|r| r.write_range()).collect();
+    assert_eq!(
+        vec![
+            0..4 * 4096,
+            4 * 4096..20 * 4096,
+            20 * 4096..21 * 4096,
+            40 * 4096..46 * 4096
+        ],
+        written
+    );
+    assert_eq!(27, coalesced.mapped_blocks());
+    assert_eq!(
+        vec![21 * 4096..40 * 4096, 46 * 4096..64 * 4096],
+        coalesced.unmapped_ranges().collect::<Vec<_>>()
+    );
+
+    let mut expected = vec![0xff; data.len()];
+    for range in written {
+        let range = range.start as usize..range.end as usize;
+        expected[range.clone()].copy_from_slice(&data[range]);
+    }
+
+    let mut output = Cursor::new(vec![0xff; data.len()]);
+    let report = bmap_parser::copy(&mut Cursor::new(&data), &mut output, &coalesced).unwrap();
+    assert_eq!(expected, output.into_inner());
+    // The merged ranges get checksums covering the bridged data as well
+    let merged = report.coalesced().unwrap();
+    let ranges: Vec<_> = merged.block_map().map(|r| r.write_range()).collect();
+    assert_eq!(vec![0..21 * 4096, 40 * 4096..46 * 4096], ranges);
+    assert_eq!(27, merged.mapped_blocks());
+    let checksums: Vec<_> = merged.block_map().map(|r| r.checksum()).collect();
+    assert_eq!(
+        vec![
+            HashValue::Sha256(Sha256::digest(&data[0..21 * 4096]).into()),
+            bmap.block_map().last().unwrap().checksum()
+        ],
+        checksums
+    );
+
+    let mut output = futures::io::Cursor::new(vec![0xff; data.len()]);
+    let async_report = futures::executor::block_on(bmap_parser::copy_async(
+        &mut futures::io::Cursor::new(&data),
+        &mut output,
+        &coalesced,
+    ))
+    .unwrap();
+    assert_eq!(expected, output.into_inner());
+    assert_eq!(report.coalesced(), async_report.coalesced());
+
+    // The merged map verifies the bridged data on later copies
+    let mut output = Cursor::new(vec![0xff; data.len()]);
+    let report = bmap_parser::copy(&mut Cursor::new(&data), &mut output, merged).unwrap();
+    assert_eq!(expected, output.into_inner());
+    assert_eq!(None, report.coalesced());
+    let mut corrupted = data.clone();
+    corrupted[10 * 4096] ^= 0xff;
+    assert!(matches!(
+        bmap_parser::copy(
+            &mut Cursor::new(&corrupted),
+            &mut Cursor::new(Vec::new()),
+            merged
+        ),
+        Err(bmap_parser::CopyError::ChecksumError)
+    ));
+
+    // Each coalesced range is still verified on its own, failing ones not getting merged
+    corrupted[5 * 4096] ^= 0xff;
+    assert!(matches!(
+        bmap_parser::copy(
+            &mut Cursor::new(&corrupted),
+            &mut Cursor::new(Vec::new()),
+            &coalesced
+        ),
+        Err(bmap_parser::CopyError::ChecksumError)
+    ));
+    let mut options = CopyOptions::new();
+    options.continue_on_error(true);
+    let report = bmap_parser::copy_with_options(
+        &mut Cursor::new(&corrupted),
+        &mut Cursor::new(Vec::new()),
+        &coalesced,
+        &options,
+    )
+    .unwrap();
+    assert_eq!(
+        std::slice::from_ref(&(4 * 4096..8 * 4096)),
+        report.checksum_errors()
+    );
+    assert_eq!(None, report.coalesced());
+}
+
+#[test]
+fn copy_image_digest() {
+    let (bmap, mut data) = generate_data();
+    let expected = HashValue::Sha256(Sha256::digest(&data).into());
+    // Neither holes nor the padding copied along are part of the image
+    for range in bmap.unmapped_ranges() {
+        data[range.start as usize..range.end as usize].fill(0x5a);
+    }
+    let aligned = bmap.align_writes(16 * 4096, 0);
+    let coalesced = bmap.coalesce(16 * 4096);
+    let mut options = CopyOptions::new();
+    options.image_digest(true);
+
+    for bmap in [&bmap, &aligned, &coalesced] {
+        let mut output = Cursor::new(Vec::new());
+        let report =
+            bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, bmap, &options)
+                .unwrap();
+        assert_eq!(Some(expected), report.image_digest());
+
+        let mut output = futures::io::Cursor::new(Vec::new());
+        let report = futures::executor::block_on(bmap_parser::copy_async_with_options(
+            &mut futures::io::Cursor::new(&data),
+            &mut output,
+            bmap,
+            &options,
+        ))
+        .unwrap();
+        assert_eq!(Some(expected), report.image_digest());
+    }
+
+    let report = bmap_parser::copy_with_options(
+        &mut Cursor::new(&data),
+        &mut Cursor::new(Vec::new()),
+        &bmap,
+        &CopyOptions::new(),
+    )
+    .unwrap();
+    assert_eq!(None, report.image_digest());
+}
+
+#[test]
+fn copy_rate_limited_holes() {
+    let (bmap, data) = generate_data();
+    let mut options = CopyOptions::new();
+    options.limit_input_rate(256 * 1024);
+
+    // Seeking over the holes of a plain file doesn't read anything
+    let start = Instant::now();
+    bmap_parser::copy_with_options(
+        &mut Cursor::new(&data),
+        &mut Cursor::new(Vec::new()),
+        &bmap,
+        &options,
+    )
+    .unwrap();
+    assert!(start.elapsed() < Duration::from_millis(400));
+
+    // While inputs reading through the holes count them as well
+    options.input_reads_seeks(true);
+    let start = Instant::now();
+    bmap_parser::copy_with_options(
+        &mut Cursor::new(&data),
+        &mut Cursor::new(Vec::new()),
+        &bmap,
+        &options,
+    )
+    .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(600));
+
+    let start = Instant::now();
+    futures::executor::block_on(bmap_parser::copy_async_with_options(
+        &mut futures::io::Cursor::new(&data),
+        &mut futures::io::Cursor::new(Vec::new()),
+        &bmap,
+        &options,
+    ))
+    .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(600));
+}
+
+#[test]
+fn copy_continue_on_error() {
+    let (bmap, mut data) = generate_data();
+    // Corrupt a range, which still gets written
+    data[20 * 4096] ^= 0xff;
+    let corrupted = 20 * 4096..21 * 4096;
+    let bad_block = 5 * 4096..6 * 4096;
+    let mut options = CopyOptions::new();
+    options.continue_on_error(true).zero_holes(true);
+
+    // The whole block gets reported, while only the data from the bad bytes on is missing
+    let bad = 5 * 4096 + 100..5 * 4096 + 200;
+    let mut expected = data.clone();
+    expected[bad.start as usize..6 * 4096].fill(0xff);
+
+    let mut output = BadOutput {
+        inner: Cursor::new(vec![0xff; data.len()]),
+        bad: bad.clone(),
+    };
+    let report =
+        bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options)
+            .unwrap();
+    assert_eq!(std::slice::from_ref(&bad_block), report.write_errors());
+    assert_eq!(std::slice::from_ref(&corrupted), report.checksum_errors());
+    assert_eq!(expected, output.inner.into_inner());
+
+    let mut output = futures::io::AllowStdIo::new(BadOutput {
+        inner: Cursor::new(vec![0xff; data.len()]),
+        bad: bad.clone(),
+    });
+    let report = futures::executor::block_on(bmap_parser::copy_async_with_options(
+        &mut futures::io::Cursor::new(&data),
+        &mut output,
+        &bmap,
+        &options,
+    ))
+    .unwrap();
+    assert_eq!(std::slice::from_ref(&bad_block), report.write_errors());
+    assert_eq!(std::slice::from_ref(&corrupted), report.checksum_errors());
+    assert_eq!(expected, output.into_inner().inner.into_inner());
+
+    // Without continuing the first error aborts the copy
+    let mut output = BadOutput {
+        inner: Cursor::new(vec![0xff; data.len()]),
+        bad,
+    };
+    assert!(matches!(
+        bmap_parser::copy(&mut Cursor::new(&data), &mut output, &bmap),
+        Err(bmap_parser::CopyError::WriteError(_))
+    ));
+}
+
+#[test]
+fn copy_report() {
+    const BLOCK_SIZE: u64 = 4096;
+    let (bmap, data) = generate_data();
+    let mut options = CopyOptions::new();
+    options.dest_offset(BLOCK_SIZE);
+
+    let mut output = Cursor::new(Vec::new());
+    let report =
+        bmap_parser::copy_with_options(&mut Cursor::new(&data), &mut output, &bmap, &options)
+            .unwrap();
+    assert_eq!(4, report.ranges());
+    assert_eq!(12 * BLOCK_SIZE, report.bytes_read());
+    assert_eq!(34 * BLOCK_SIZE, report.bytes_discarded());
+    assert_eq!(12 * BLOCK_SIZE, report.bytes_written());
+    assert_eq!(34 * BLOCK_SIZE, report.bytes_skipped());
+    assert!(report.elapsed() >= report.decode_time() + report.hash_time() + report.write_time());
+    assert!(report.throughput() > 0);
+
+    options.dest_offset(0).zero_holes(true);
+    let mut output = futures::io::Cursor::new(Vec::new());
+    let report = futures::executor::block_on(bmap_parser::copy_async_with_options(
+        &mut futures::io::Cursor::new(&data),
+        &mut output,
+        &bmap,
+        &options,
+    ))
+    .unwrap();
+    assert_eq!(4, report.ranges());
+    assert_eq!(12 * BLOCK_SIZE, report.bytes_read());
+    assert_eq!(64 * BLOCK_SIZE, report.bytes_written());
+    assert_eq!(0, report.bytes_skipped());
+}
@@ -1,4 +1,4 @@
-use bmap_parser::Bmap;
+use bmap_parser::{Bmap, HashType, HashValue};
 use digest::Digest;
 use sha2::Sha256;
 
@@ -24,3 +24,38 @@ fn parse() {
     }
     assert_eq!(2048, block);
 }
+
+#[test]
+fn unmapped_ranges() {
+    let xml = include_str!("data/simple.bmap");
+    let bmap = Bmap::from_xml(xml).unwrap();
+
+    let unmapped: Vec<_> = bmap.unmapped_ranges().collect();
+    assert_eq!(
+        vec![
+            4096..8 * 4096,
+            17 * 4096..32 * 4096,
+            65 * 4096..128 * 4096,
+            257 * 4096..512 * 4096,
+        ],
+        unmapped
+    );
+
+    let unmapped: u64 = unmapped.iter().map(|r| r.end - r.start).sum();
+    let mapped: u64 = bmap.block_map().map(|r| r.length()).sum();
+    assert_eq!(bmap.image_size(), mapped + unmapped);
+
+    let mut builder = Bmap::builder();
+    builder
+        .image_size(16384)
+        .block_size(4096)
+        .blocks(4)
+        .mapped_blocks(1)
+        .checksum_type(HashType::Sha256)
+        .add_block_range(1, 1, HashValue::Sha256([0; 32]));
+    let bmap = builder.build().unwrap();
+    assert_eq!(
+        vec![0..4096, 8192..16384],
+        bmap.unmapped_ranges().collect::<Vec<_>>()
+    );
+}
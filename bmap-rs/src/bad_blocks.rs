@@ -0,0 +1,47 @@
+use anyhow::{Context, Result};
+use bmap_parser::CopyReport;
+use serde::Serialize;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+/// Byte range of the destination
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Region {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Machine-readable account of the errors skipped over while copying
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ErrorReport<'a> {
+    pub destination: &'a Path,
+    /// Blocks which couldn't be written
+    pub bad_blocks: Vec<Region>,
+    /// Ranges which were written but don't match their checksum
+    pub unverified: Vec<Region>,
+}
+
+impl<'a> ErrorReport<'a> {
+    pub fn new(destination: &'a Path, report: &CopyReport, dest_offset: u64) -> Self {
+        let regions = |ranges: &[Range<u64>]| {
+            ranges
+                .iter()
+                .map(|r| Region {
+                    offset: dest_offset + r.start,
+                    length: r.end - r.start,
+                })
+                .collect()
+        };
+        Self {
+            destination,
+            bad_blocks: regions(report.write_errors()),
+            unverified: regions(report.checksum_errors()),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(path, json + "\n").with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
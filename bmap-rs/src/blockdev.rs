@@ -0,0 +1,848 @@
+use anyhow::{Context, Result, bail};
+use nix::sys::stat::{major, minor};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Locations of the proc, sys and dev filesystems, which can point at fixtures for testing
+#[derive(Clone, Debug)]
+pub struct System {
+    pub proc_root: PathBuf,
+    pub sys_root: PathBuf,
+    pub dev_root: PathBuf,
+}
+
+impl Default for System {
+    fn default() -> Self {
+        Self {
+            proc_root: PathBuf::from("/proc"),
+            sys_root: PathBuf::from("/sys"),
+            dev_root: PathBuf::from("/dev"),
+        }
+    }
+}
+
+/// A block device or partition as known to the kernel
+#[derive(Clone, Debug)]
+struct Node {
+    name: String,
+    dev: String,
+    sysfs: PathBuf,
+}
+
+impl Node {
+    fn from_sysfs(sysfs: PathBuf) -> Result<Self> {
+        let uevent = read_uevent(&sysfs)?;
+        let field = |key: &str| {
+            uevent
+                .get(key)
+                .cloned()
+                .with_context(|| format!("No {key} in {}", sysfs.display()))
+        };
+        Ok(Node {
+            name: field("DEVNAME")?,
+            dev: format!("{}:{}", field("MAJOR")?, field("MINOR")?),
+            sysfs,
+        })
+    }
+}
+
+fn read_uevent(sysfs: &Path) -> Result<HashMap<String, String>> {
+    let path = sysfs.join("uevent");
+    let uevent =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(uevent
+        .lines()
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect())
+}
+
+/// Undo the octal escaping of whitespace and backslashes used in /proc files
+fn unescape(field: &str) -> String {
+    let mut out = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let code = rest
+            .get(i + 1..i + 4)
+            .and_then(|c| u8::from_str_radix(c, 8).ok());
+        match code {
+            Some(c) => {
+                out.push(c as char);
+                rest = &rest[i + 4..];
+            }
+            None => {
+                out.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A mounted block device
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Mount {
+    pub device: String,
+    pub target: String,
+}
+
+/// A whole disk which could be written to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Disk {
+    pub path: PathBuf,
+    pub size: u64,
+    pub removable: bool,
+    pub usb: bool,
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub mounts: Vec<Mount>,
+}
+
+/// Criteria selecting a disk by its identity rather than its kernel name, which can change
+/// between boots
+#[derive(Clone, Debug, Default)]
+pub struct DiskMatch {
+    pub serial: Option<String>,
+    pub id: Option<String>,
+    pub model: Option<String>,
+}
+
+impl DiskMatch {
+    pub fn is_empty(&self) -> bool {
+        self.serial.is_none() && self.id.is_none() && self.model.is_none()
+    }
+}
+
+impl fmt::Display for DiskMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let criteria: Vec<_> = [
+            ("serial", &self.serial),
+            ("id", &self.id),
+            ("model", &self.model),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some(format!("{name} {}", value.as_ref()?)))
+        .collect();
+        write!(f, "{}", criteria.join(", "))
+    }
+}
+
+/// Vendor and model of a disk, as far as they're known
+fn describe(vendor: Option<&str>, model: Option<&str>) -> String {
+    [vendor, model]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Disk {
+    /// Vendor and model of the disk, as far as they're known
+    pub fn description(&self) -> String {
+        describe(self.vendor.as_deref(), self.model.as_deref())
+    }
+
+    fn matches(&self, m: &DiskMatch, id_target: Option<&str>) -> bool {
+        let name = self.path.file_name().and_then(|n| n.to_str());
+        let model = self.model.as_deref();
+        let full_model = self.description();
+        m.serial
+            .as_ref()
+            .is_none_or(|s| self.serial.as_ref() == Some(s))
+            && id_target.is_none_or(|t| name == Some(t))
+            && m.model
+                .as_deref()
+                .is_none_or(|m| model == Some(m) || full_model == m)
+    }
+}
+
+/// Read a sysfs attribute, treating missing and empty ones as unset
+fn read_attribute(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// A process as listed in proc
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Process {
+    pub pid: u32,
+    pub name: String,
+}
+
+impl fmt::Display for Process {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pid {})", self.name, self.pid)
+    }
+}
+
+/// Something keeping a block device busy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Usage {
+    Mounted { device: String, target: String },
+    Swap { device: String },
+    Held { device: String, holder: String },
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Usage::Mounted { device, target } => write!(f, "{device} is mounted at {target}"),
+            Usage::Swap { device } => write!(f, "{device} is used as swap"),
+            Usage::Held { device, holder } => write!(f, "{device} is held by {holder}"),
+        }
+    }
+}
+
+/// A partition found on a block device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionInfo {
+    pub name: String,
+    pub number: u32,
+    pub size: u64,
+    pub label: Option<String>,
+}
+
+/// What's currently on a block device, to show before overwriting it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Contents {
+    pub vendor: Option<String>,
+    pub model: Option<String>,
+    pub size: u64,
+    pub label: Option<String>,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+impl Contents {
+    /// Vendor and model of the device, as far as they're known
+    pub fn description(&self) -> String {
+        let description = describe(self.vendor.as_deref(), self.model.as_deref());
+        if description.is_empty() {
+            "unknown model".to_string()
+        } else {
+            description
+        }
+    }
+}
+
+/// Undo the \xNN escaping udev uses for characters which aren't safe in link names
+fn unescape_udev(name: &str) -> String {
+    let mut out = Vec::with_capacity(name.len());
+    let mut rest = name.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let code = tail
+            .strip_prefix(b"x")
+            .and_then(|t| t.get(..2))
+            .and_then(|c| std::str::from_utf8(c).ok())
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+        match code {
+            Some(c) if b == b'\\' => {
+                out.push(c);
+                rest = &tail[3..];
+            }
+            _ => {
+                out.push(b);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// sysfs directory of the disk a block device is on, i.e. the device itself unless it's a
+/// partition
+pub fn disk_dir(sysfs: &Path) -> &Path {
+    match sysfs.join("partition").exists() {
+        true => sysfs.parent().unwrap_or(sysfs),
+        false => sysfs,
+    }
+}
+
+/// Size of a block device or partition in bytes
+fn read_size(sysfs: &Path) -> u64 {
+    read_attribute(&sysfs.join("size"))
+        .and_then(|s| s.parse::<u64>().ok())
+        // sysfs always counts in 512 byte sectors
+        .map_or(0, |s| s * 512)
+}
+
+impl System {
+    /// The block device with the given device number and its partitions
+    fn device_with_partitions(&self, rdev: u64) -> Result<Vec<Node>> {
+        let sysfs =
+            self.sys_root
+                .join("dev/block")
+                .join(format!("{}:{}", major(rdev), minor(rdev)));
+        Self::with_partitions(sysfs)
+    }
+
+    /// The block device at the given sysfs directory and its partitions
+    fn with_partitions(sysfs: PathBuf) -> Result<Vec<Node>> {
+        let mut nodes = vec![Node::from_sysfs(sysfs.clone())?];
+        for entry in fs::read_dir(&sysfs)? {
+            let path = entry?.path();
+            if path.join("partition").exists() {
+                nodes.push(Node::from_sysfs(path)?);
+            }
+        }
+        Ok(nodes)
+    }
+
+    fn mounts(&self, nodes: &[Node]) -> Result<Vec<Usage>> {
+        let path = self.proc_root.join("self/mountinfo");
+        let mountinfo = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut usages = Vec::new();
+        for line in mountinfo.lines() {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (Some(dev), Some(target)) = (fields.get(2), fields.get(4)) else {
+                continue;
+            };
+            if let Some(node) = nodes.iter().find(|n| n.dev == *dev) {
+                usages.push(Usage::Mounted {
+                    device: node.name.clone(),
+                    target: unescape(target),
+                });
+            }
+        }
+        Ok(usages)
+    }
+
+    fn swaps(&self, nodes: &[Node]) -> Result<Vec<Usage>> {
+        let path = self.proc_root.join("swaps");
+        let swaps = match fs::read_to_string(&path) {
+            Ok(swaps) => swaps,
+            // Kernels without swap support don't have the file at all
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let mut usages = Vec::new();
+        for line in swaps.lines().skip(1) {
+            let Some(filename) = line.split_whitespace().next() else {
+                continue;
+            };
+            let filename = PathBuf::from(unescape(filename));
+            let name = filename.file_name().and_then(|n| n.to_str());
+            if let Some(node) = nodes.iter().find(|n| Some(n.name.as_str()) == name) {
+                usages.push(Usage::Swap {
+                    device: node.name.clone(),
+                });
+            }
+        }
+        Ok(usages)
+    }
+
+    fn holders(&self, nodes: &[Node]) -> Result<Vec<Usage>> {
+        let mut usages = Vec::new();
+        for node in nodes {
+            let entries = match fs::read_dir(node.sysfs.join("holders")) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                usages.push(Usage::Held {
+                    device: node.name.clone(),
+                    holder: entry?.file_name().to_string_lossy().into_owned(),
+                });
+            }
+        }
+        Ok(usages)
+    }
+
+    /// Name of the whole disk the block device with the given device number is a partition of, or
+    /// `None` if it isn't a partition
+    pub fn whole_disk(&self, rdev: u64) -> Result<Option<String>> {
+        let sysfs =
+            self.sys_root
+                .join("dev/block")
+                .join(format!("{}:{}", major(rdev), minor(rdev)));
+        if !sysfs.join("partition").exists() {
+            return Ok(None);
+        }
+        Ok(Some(Node::from_sysfs(sysfs.join(".."))?.name))
+    }
+
+    /// Process holding a lock on the file with the given inode on the filesystem with the given
+    /// device number, if any
+    pub fn lock_holder(&self, dev: u64, ino: u64) -> Result<Option<Process>> {
+        let path = self.proc_root.join("locks");
+        let locks = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let file = format!("{:02x}:{:02x}:{}", major(dev), minor(dev), ino);
+        // Lines of waiters are marked with "->" and come after the lock they're blocked on
+        let holder = locks
+            .lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>())
+            .filter(|fields| fields.get(1) != Some(&"->"))
+            .find(|fields| fields.get(5) == Some(&file.as_str()))
+            .and_then(|fields| fields.get(4)?.parse::<u32>().ok());
+        let Some(pid) = holder else {
+            return Ok(None);
+        };
+        let name = fs::read_to_string(self.proc_root.join(pid.to_string()).join("comm"))
+            .map(|comm| comm.trim_end().to_string())
+            .unwrap_or_else(|_| "unknown".to_string());
+        Ok(Some(Process { pid, name }))
+    }
+
+    /// Serial number of a device, which for USB devices is found on the USB device further up the
+    /// device tree
+    fn serial(sys_root: &Path, device: &Path) -> Option<String> {
+        let devices = sys_root.join("devices");
+        device
+            .ancestors()
+            .take_while(|dir| dir.starts_with(&devices))
+            .find_map(|dir| read_attribute(&dir.join("serial")))
+    }
+
+    /// All physical disks with media present. Virtual block devices such as loop, device mapper
+    /// or zram devices are skipped.
+    pub fn disks(&self) -> Result<Vec<Disk>> {
+        // Device paths get compared against the resolved links in the block directory
+        let sys_root = fs::canonicalize(&self.sys_root)
+            .with_context(|| format!("Failed to resolve {}", self.sys_root.display()))?;
+        let block = sys_root.join("block");
+        let virtual_devices = sys_root.join("devices/virtual");
+        let entries = fs::read_dir(&block)
+            .with_context(|| format!("Failed to read {}", block.display()))?
+            .map(|entry| Ok(fs::canonicalize(entry?.path())?))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut disks = Vec::new();
+        for sysfs in entries {
+            let size = read_size(&sysfs);
+            if sysfs.starts_with(&virtual_devices) || size == 0 {
+                continue;
+            }
+            let nodes = Self::with_partitions(sysfs.clone())?;
+            let device = fs::canonicalize(sysfs.join("device")).ok();
+            let mounts = self
+                .mounts(&nodes)?
+                .into_iter()
+                .filter_map(|usage| match usage {
+                    Usage::Mounted { device, target } => Some(Mount { device, target }),
+                    _ => None,
+                })
+                .collect();
+            disks.push(Disk {
+                path: self.dev_root.join(&nodes[0].name),
+                size,
+                removable: read_attribute(&sysfs.join("removable")).as_deref() == Some("1"),
+                usb: sysfs
+                    .components()
+                    .any(|c| c.as_os_str().to_string_lossy().starts_with("usb")),
+                vendor: read_attribute(&sysfs.join("device/vendor")),
+                model: read_attribute(&sysfs.join("device/model")),
+                serial: device.and_then(|d| Self::serial(&sys_root, &d)),
+                mounts,
+            });
+        }
+        disks.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(disks)
+    }
+
+    /// Kernel name of the device a /dev/disk/by-id link points to
+    fn resolve_id(&self, id: &str) -> Result<String> {
+        let link = self.dev_root.join("disk/by-id").join(id);
+        let target = fs::read_link(&link).with_context(|| format!("No device with id {id}"))?;
+        target
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .with_context(|| format!("Invalid link {}", link.display()))
+    }
+
+    /// The only disk matching all given criteria. Fails if no disk or more than one matches.
+    pub fn find_disk(&self, m: &DiskMatch) -> Result<Disk> {
+        let id_target = m.id.as_deref().map(|id| self.resolve_id(id)).transpose()?;
+        let mut disks: Vec<_> = self
+            .disks()?
+            .into_iter()
+            .filter(|d| d.matches(m, id_target.as_deref()))
+            .collect();
+        match disks.len() {
+            0 => bail!("No disk matches {m}"),
+            1 => Ok(disks.remove(0)),
+            n => {
+                let paths: Vec<_> = disks.iter().map(|d| d.path.display().to_string()).collect();
+                bail!("{n} disks match {m}: {}", paths.join(", "))
+            }
+        }
+    }
+
+    /// Filesystem labels by kernel name of the device they're on, as linked by udev in
+    /// /dev/disk/by-label. Without udev no labels are known.
+    fn labels(&self) -> Result<HashMap<String, String>> {
+        let dir = self.dev_root.join("disk/by-label");
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+        };
+        let mut labels = HashMap::new();
+        for entry in entries {
+            let entry = entry?;
+            let target = fs::read_link(entry.path())?;
+            if let Some(name) = target.file_name() {
+                labels.insert(
+                    name.to_string_lossy().into_owned(),
+                    unescape_udev(&entry.file_name().to_string_lossy()),
+                );
+            }
+        }
+        Ok(labels)
+    }
+
+    /// Model, size, partitions and filesystem labels of the block device with the given device
+    /// number. For a partition the model is the one of the disk it's on.
+    pub fn contents(&self, rdev: u64) -> Result<Contents> {
+        let sysfs = self.sysfs_device(rdev)?;
+        let nodes = Self::with_partitions(sysfs.clone())?;
+        let mut labels = self.labels()?;
+        let disk = disk_dir(&sysfs);
+
+        let mut partitions = nodes[1..]
+            .iter()
+            .map(|node| PartitionInfo {
+                name: node.name.clone(),
+                number: read_attribute(&node.sysfs.join("partition"))
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(0),
+                size: read_size(&node.sysfs),
+                label: labels.remove(&node.name),
+            })
+            .collect::<Vec<_>>();
+        partitions.sort_by_key(|p| p.number);
+        Ok(Contents {
+            vendor: read_attribute(&disk.join("device/vendor")),
+            model: read_attribute(&disk.join("device/model")),
+            size: read_size(&sysfs),
+            label: labels.remove(&nodes[0].name),
+            partitions,
+        })
+    }
+
+    /// The USB device the given disk is attached through, whose port can be switched off
+    fn usb_device(&self, disk: &str) -> Result<PathBuf> {
+        let link = self.sys_root.join("block").join(disk);
+        let sysfs = fs::canonicalize(&link)
+            .with_context(|| format!("Failed to resolve {}", link.display()))?;
+        sysfs
+            .ancestors()
+            .find(|dir| {
+                dir.join("remove").exists()
+                    && dir
+                        .parent()
+                        .and_then(|p| p.file_name())
+                        .is_some_and(|n| n.to_string_lossy().starts_with("usb"))
+            })
+            .map(Path::to_path_buf)
+            .with_context(|| format!("{disk} isn't attached over USB"))
+    }
+
+    /// Detach the given disk from the kernel by deleting its SCSI device, which has the drive
+    /// flush its cache and stop
+    pub fn eject(&self, disk: &str) -> Result<()> {
+        let path = self.sys_root.join("block").join(disk).join("device/delete");
+        if !path.exists() {
+            bail!("{disk} can't be ejected as it isn't a SCSI disk");
+        }
+        fs::write(&path, "1").with_context(|| format!("Failed to eject {disk}"))
+    }
+
+    /// Eject the given disk and switch off the USB port it's attached to, after which it's safe
+    /// to unplug
+    pub fn power_off(&self, disk: &str) -> Result<()> {
+        let usb = self.usb_device(disk)?;
+        self.eject(disk)?;
+        fs::write(usb.join("remove"), "1").with_context(|| format!("Failed to power off {disk}"))
+    }
+
+    /// Resolved sysfs directory of the block device with the given device number
+    pub fn sysfs_device(&self, rdev: u64) -> Result<PathBuf> {
+        let link = self
+            .sys_root
+            .join("dev/block")
+            .join(format!("{}:{}", major(rdev), minor(rdev)));
+        fs::canonicalize(&link).with_context(|| format!("Failed to resolve {}", link.display()))
+    }
+
+    /// Preferred write unit of the disk the block device with the given device number is on:
+    /// the erase block size of SD cards and eMMC, or else the discard granularity
+    pub fn erase_size(&self, rdev: u64) -> Result<Option<u64>> {
+        let sysfs = self.sysfs_device(rdev)?;
+        let disk = disk_dir(&sysfs);
+        Ok(["device/preferred_erase_size", "queue/discard_granularity"]
+            .into_iter()
+            .filter_map(|attr| read_attribute(&disk.join(attr))?.parse::<u64>().ok())
+            .find(|&size| size > 0))
+    }
+
+    /// Largest discard the queue of the disk the block device with the given device number is on
+    /// accepts, 0 if it doesn't support discarding
+    pub fn discard_max_bytes(&self, rdev: u64) -> Result<u64> {
+        let sysfs = self.sysfs_device(rdev)?;
+        Ok(
+            read_attribute(&disk_dir(&sysfs).join("queue/discard_max_bytes"))
+                .and_then(|s| s.parse::<u64>().ok())
+                .unwrap_or(0),
+        )
+    }
+
+    /// Offset in bytes of the partition with the given device number on its disk, 0 for whole
+    /// disks
+    pub fn partition_start(&self, rdev: u64) -> Result<u64> {
+        let sysfs = self.sysfs_device(rdev)?;
+        Ok(read_attribute(&sysfs.join("start"))
+            .and_then(|s| s.parse::<u64>().ok())
+            // sysfs always counts in 512 byte sectors
+            .map_or(0, |s| s * 512))
+    }
+
+    /// Find everything keeping the block device with the given device number or any of its
+    /// partitions busy: mounts, swap and holders such as device mapper or md devices
+    pub fn users(&self, rdev: u64) -> Result<Vec<Usage>> {
+        let nodes = self.device_with_partitions(rdev)?;
+        let mut usages = self.mounts(&nodes)?;
+        usages.extend(self.swaps(&nodes)?);
+        usages.extend(self.holders(&nodes)?);
+        Ok(usages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::sys::stat::makedev;
+
+    fn fixture() -> System {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/system");
+        System {
+            proc_root: root.join("proc"),
+            sys_root: root.join("sys"),
+            dev_root: root.join("dev"),
+        }
+    }
+
+    #[test]
+    fn users() {
+        let system = fixture();
+        let users = system.users(makedev(8, 0)).unwrap();
+        assert_eq!(
+            vec![
+                Usage::Mounted {
+                    device: "sda1".to_string(),
+                    target: "/media/boot files".to_string()
+                },
+                Usage::Mounted {
+                    device: "sda1".to_string(),
+                    target: "/mnt".to_string()
+                },
+                Usage::Swap {
+                    device: "sda2".to_string()
+                },
+                Usage::Held {
+                    device: "sda3".to_string(),
+                    holder: "dm-0".to_string()
+                },
+            ],
+            users
+        );
+
+        assert!(system.users(makedev(8, 16)).unwrap().is_empty());
+        assert!(system.users(makedev(8, 32)).is_err());
+    }
+
+    #[test]
+    fn whole_disk() {
+        let system = fixture();
+        assert_eq!(
+            Some("sda".to_string()),
+            system.whole_disk(makedev(8, 1)).unwrap()
+        );
+        assert_eq!(None, system.whole_disk(makedev(8, 0)).unwrap());
+    }
+
+    #[test]
+    fn lock_holder() {
+        let system = fixture();
+        assert_eq!(
+            Some(Process {
+                pid: 4242,
+                name: "udisksd".to_string()
+            }),
+            system.lock_holder(makedev(0, 6), 321).unwrap()
+        );
+        assert_eq!(None, system.lock_holder(makedev(0, 6), 1).unwrap());
+        assert_eq!(None, system.lock_holder(makedev(0, 6), 654).unwrap());
+    }
+
+    #[test]
+    fn disks() {
+        let system = fixture();
+        let root = &system.dev_root;
+        let disks = system.disks().unwrap();
+        assert_eq!(
+            vec![
+                Disk {
+                    path: root.join("sda"),
+                    size: 976773168 * 512,
+                    removable: false,
+                    usb: false,
+                    vendor: Some("ATA".to_string()),
+                    model: Some("Samsung SSD 860".to_string()),
+                    serial: None,
+                    mounts: vec![
+                        Mount {
+                            device: "sda1".to_string(),
+                            target: "/media/boot files".to_string()
+                        },
+                        Mount {
+                            device: "sda1".to_string(),
+                            target: "/mnt".to_string()
+                        },
+                    ],
+                },
+                Disk {
+                    path: root.join("sdb"),
+                    size: 30031872 * 512,
+                    removable: true,
+                    usb: true,
+                    vendor: Some("SanDisk".to_string()),
+                    model: Some("Cruzer Blade".to_string()),
+                    serial: Some("4C530001230101112233".to_string()),
+                    mounts: vec![],
+                },
+            ],
+            disks
+        );
+    }
+
+    #[test]
+    fn find_disk() {
+        let system = fixture();
+        let find = |serial: Option<&str>, id: Option<&str>, model: Option<&str>| {
+            system
+                .find_disk(&DiskMatch {
+                    serial: serial.map(str::to_string),
+                    id: id.map(str::to_string),
+                    model: model.map(str::to_string),
+                })
+                .map(|d| d.path.file_name().unwrap().to_string_lossy().into_owned())
+        };
+
+        assert_eq!(
+            "sdb",
+            find(Some("4C530001230101112233"), None, None).unwrap()
+        );
+        assert_eq!("sdb", find(None, None, Some("Cruzer Blade")).unwrap());
+        assert_eq!(
+            "sdb",
+            find(None, None, Some("SanDisk Cruzer Blade")).unwrap()
+        );
+        assert_eq!(
+            "sda",
+            find(None, Some("ata-Samsung_SSD_860_S3Z9NB0K"), None).unwrap()
+        );
+        assert_eq!(
+            "sdb",
+            find(
+                Some("4C530001230101112233"),
+                Some("usb-SanDisk_Cruzer_Blade_4C530001230101112233-0:0"),
+                Some("Cruzer Blade")
+            )
+            .unwrap()
+        );
+
+        // Criteria contradicting each other, a partition, an unknown id and too many matches
+        assert!(
+            find(
+                Some("4C530001230101112233"),
+                Some("ata-Samsung_SSD_860_S3Z9NB0K"),
+                None
+            )
+            .is_err()
+        );
+        assert!(
+            find(
+                None,
+                Some("usb-SanDisk_Cruzer_Blade_4C530001230101112233-0:0-part1"),
+                None
+            )
+            .is_err()
+        );
+        assert!(find(None, Some("nvme-missing"), None).is_err());
+        assert!(find(None, None, None).is_err());
+    }
+
+    #[test]
+    fn unescape_fields() {
+        assert_eq!("/media/a b", unescape("/media/a\\040b"));
+        assert_eq!("a\\b\\", unescape("a\\134b\\"));
+        assert_eq!("CRUZER BOOT", unescape_udev("CRUZER\\x20BOOT"));
+        assert_eq!("a/b\\x2", unescape_udev("a\\x2fb\\x2"));
+    }
+
+    #[test]
+    fn usb_device() {
+        let system = fixture();
+        let usb = system.usb_device("sdb").unwrap();
+        assert!(usb.ends_with("devices/pci0000:00/0000:00:14.0/usb2/2-1"));
+        assert!(system.usb_device("sda").is_err());
+    }
+
+    #[test]
+    fn write_geometry() {
+        let system = fixture();
+        assert_eq!(Some(4096), system.erase_size(makedev(8, 0)).unwrap());
+        assert_eq!(Some(4096), system.erase_size(makedev(8, 1)).unwrap());
+        assert_eq!(None, system.erase_size(makedev(8, 16)).unwrap());
+        assert_eq!(0, system.partition_start(makedev(8, 0)).unwrap());
+        assert_eq!(1024 * 1024, system.partition_start(makedev(8, 1)).unwrap());
+    }
+
+    #[test]
+    fn contents() {
+        let system = fixture();
+        let contents = system.contents(makedev(8, 0)).unwrap();
+        assert_eq!("ATA Samsung SSD 860", contents.description());
+        assert_eq!(976773168 * 512, contents.size);
+        assert_eq!(None, contents.label);
+        assert_eq!(
+            vec![
+                PartitionInfo {
+                    name: "sda1".to_string(),
+                    number: 1,
+                    size: 512 * 1024 * 1024,
+                    label: Some("EFI".to_string()),
+                },
+                PartitionInfo {
+                    name: "sda2".to_string(),
+                    number: 2,
+                    size: 8 * 1024 * 1024 * 1024,
+                    label: None,
+                },
+                PartitionInfo {
+                    name: "sda3".to_string(),
+                    number: 3,
+                    size: 958945280 * 512,
+                    label: Some("root".to_string()),
+                },
+            ],
+            contents.partitions
+        );
+
+        let contents = system.contents(makedev(8, 1)).unwrap();
+        assert_eq!("ATA Samsung SSD 860", contents.description());
+        assert_eq!(512 * 1024 * 1024, contents.size);
+        assert_eq!(Some("EFI".to_string()), contents.label);
+        assert!(contents.partitions.is_empty());
+    }
+}
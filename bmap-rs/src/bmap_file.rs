@@ -0,0 +1,174 @@
+use crate::Image;
+use anyhow::{Context, Result, bail, ensure};
+use bmap_parser::Bmap;
+use flate2::read::GzDecoder;
+use reqwest::Url;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Start of a gzip stream
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+fn append(path: &Path, suffix: &str) -> PathBuf {
+    let mut p = OsString::from(path);
+    p.push(suffix);
+    p.into()
+}
+
+/// Names the bmap file of an image could have, from the most to the least specific: dropping
+/// one extension of the image at a time, e.g. `foo.img.gz.bmap`, `foo.img.bmap` and `foo.bmap`
+/// for `foo.img.gz`, each also gzip compressed
+fn candidates(image: &Path) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    let mut base = image.to_path_buf();
+    loop {
+        let bmap = append(&base, ".bmap");
+        let compressed = append(&bmap, ".gz");
+        candidates.push(bmap);
+        candidates.push(compressed);
+        if base.extension().is_none() {
+            return candidates;
+        }
+        base.set_extension("");
+    }
+}
+
+fn remote_candidates(image: &Url) -> Vec<Url> {
+    candidates(Path::new(image.path()))
+        .iter()
+        .filter_map(|path| {
+            let mut url = image.clone();
+            url.set_path(path.to_str()?);
+            Some(url)
+        })
+        .collect()
+}
+
+/// Parse a bmap file, which may be gzip compressed
+fn parse(data: &[u8]) -> Result<Bmap> {
+    let mut xml = String::new();
+    if data.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(data)
+            .read_to_string(&mut xml)
+            .context("Failed to decompress bmap file")?;
+    } else {
+        xml = String::from_utf8(data.to_vec()).context("Bmap file isn't valid UTF-8")?;
+    }
+    Ok(Bmap::from_xml(&xml)?)
+}
+
+fn read(path: &Path) -> Result<Bmap> {
+    parse(&fs::read(path).context("Failed to open bmap file")?)
+}
+
+async fn fetch(url: &Url) -> Result<Bmap> {
+    let data = reqwest::get(url.clone())
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await
+        .with_context(|| format!("Failed to fetch {url}"))?;
+    parse(&data)
+}
+
+fn load_local(image: &Path) -> Result<Bmap> {
+    let Some(bmap) = candidates(image).into_iter().find(|p| p.exists()) else {
+        bail!("Couldn't find bmap file");
+    };
+    println!("Found bmap file: {}", bmap.display());
+    read(&bmap)
+}
+
+async fn load_remote(image: &Url) -> Result<Bmap> {
+    for url in remote_candidates(image) {
+        let res = reqwest::get(url.clone()).await?;
+        if res.status().is_success() {
+            println!("Found bmap file: {url}");
+            return parse(&res.bytes().await?);
+        }
+    }
+    bail!("Couldn't find bmap file");
+}
+
+/// Load the bmap file given on the command line as path or URL, or else the one found next to
+/// the image
+pub async fn load(bmap: Option<&str>, image: &Image) -> Result<Bmap> {
+    match (bmap, image) {
+        (Some(bmap), _) => {
+            println!("Using bmap file: {bmap}");
+            match Url::parse(bmap) {
+                Ok(url) => fetch(&url).await,
+                Err(_) => read(Path::new(bmap)),
+            }
+        }
+        (None, Image::Path(path)) => {
+            ensure!(path.exists(), "Image file doesn't exist");
+            load_local(path)
+        }
+        (None, Image::Url(url)) => load_remote(url).await,
+        (None, Image::Stdin) => {
+            bail!("The bmap file has to be given with --bmap when reading the image from stdin")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    #[test]
+    fn candidates() {
+        assert_eq!(
+            vec![
+                PathBuf::from("/tmp/foo.img.gz.bmap"),
+                PathBuf::from("/tmp/foo.img.gz.bmap.gz"),
+                PathBuf::from("/tmp/foo.img.bmap"),
+                PathBuf::from("/tmp/foo.img.bmap.gz"),
+                PathBuf::from("/tmp/foo.bmap"),
+                PathBuf::from("/tmp/foo.bmap.gz"),
+            ],
+            super::candidates(Path::new("/tmp/foo.img.gz"))
+        );
+        assert_eq!(
+            vec![PathBuf::from("foo.bmap"), PathBuf::from("foo.bmap.gz")],
+            super::candidates(Path::new("foo"))
+        );
+    }
+
+    #[test]
+    fn remote_candidates() {
+        let url = Url::parse("https://example.com/images/foo.img.gz?token=1").unwrap();
+        let candidates: Vec<_> = super::remote_candidates(&url)
+            .iter()
+            .map(Url::to_string)
+            .collect();
+        assert_eq!(
+            vec![
+                "https://example.com/images/foo.img.gz.bmap?token=1",
+                "https://example.com/images/foo.img.gz.bmap.gz?token=1",
+                "https://example.com/images/foo.img.bmap?token=1",
+                "https://example.com/images/foo.img.bmap.gz?token=1",
+                "https://example.com/images/foo.bmap?token=1",
+                "https://example.com/images/foo.bmap.gz?token=1",
+            ],
+            candidates
+        );
+    }
+
+    #[test]
+    fn parse_compressed() {
+        let xml = include_str!("../../bmap-parser/tests/data/simple.bmap");
+        let plain = parse(xml.as_bytes()).unwrap();
+
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(xml.as_bytes()).unwrap();
+        let compressed = parse(&gz.finish().unwrap()).unwrap();
+        assert_eq!(plain.image_size(), compressed.image_size());
+        assert_eq!(plain.total_mapped_size(), compressed.total_mapped_size());
+    }
+}
@@ -0,0 +1,250 @@
+use crate::blockdev::System;
+use anyhow::{Context, Result, bail};
+use nix::errno::Errno;
+use nix::fcntl::{FallocateFlags, Flock, FlockArg, fallocate};
+use nix::libc::c_int;
+use nix::sys::statvfs::fstatvfs;
+use nix::{ioctl_none_bad, ioctl_read, ioctl_read_bad, ioctl_write_ptr_bad, request_code_none};
+use std::fs::{File, Metadata};
+use std::ops::Range;
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+ioctl_none_bad!(blkrrpart, request_code_none!(0x12, 95));
+ioctl_none_bad!(blkflsbuf, request_code_none!(0x12, 97));
+ioctl_read_bad!(blksszget, request_code_none!(0x12, 104), c_int);
+ioctl_write_ptr_bad!(blkdiscard, request_code_none!(0x12, 119), [u64; 2]);
+ioctl_write_ptr_bad!(blkzeroout, request_code_none!(0x12, 127), [u64; 2]);
+ioctl_read!(blkgetsize64, 0x12, 114, u64);
+
+/// Size of the destination if it can't grow, i.e. it's a block device
+pub fn fixed_size<T: AsFd>(output: &T, metadata: &Metadata) -> Result<Option<u64>> {
+    if !metadata.file_type().is_block_device() {
+        return Ok(None);
+    }
+    let mut size = 0;
+    // SAFETY: BLKGETSIZE64 only writes a single u64
+    unsafe { blkgetsize64(output.as_fd().as_raw_fd(), &mut size) }
+        .context("Failed to query block device size")?;
+    Ok(Some(size))
+}
+
+/// Space a regular file can take up, i.e. the free space on its filesystem plus the space it
+/// already occupies
+pub fn available_space<T: AsFd>(output: &T, metadata: &Metadata) -> Result<u64> {
+    let stat = fstatvfs(output.as_fd()).context("Failed to query free space")?;
+    Ok(stat.blocks_available() * stat.fragment_size() + metadata.blocks() * 512)
+}
+
+/// Have the kernel re-read the partition table of a whole disk, so the partitions just written
+/// show up
+pub fn reread_partitions<T: AsFd>(disk: &T) -> Result<()> {
+    // SAFETY: BLKRRPART doesn't take an argument
+    unsafe { blkrrpart(disk.as_fd().as_raw_fd()) }.context("Failed to re-read partition table")?;
+    Ok(())
+}
+
+/// Write back everything cached for a block device and drop the kernel's buffers for it. Syncing
+/// a block device also has the device flush its own write cache.
+pub fn flush_cache(disk: &File) -> Result<()> {
+    disk.sync_all().context("Failed to flush write cache")?;
+    // SAFETY: BLKFLSBUF doesn't take an argument
+    unsafe { blkflsbuf(disk.as_raw_fd()) }.context("Failed to flush buffers")?;
+    Ok(())
+}
+
+/// How long to keep trying to take a lock, as udev holds one while probing a device
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Advisory lock on a destination, released when dropped
+pub struct Lock {
+    _flock: Flock<File>,
+}
+
+/// Take an exclusive advisory lock on a destination. Following the systemd block device locking
+/// convention, partitions get locked through their whole disk, which also keeps udev from probing
+/// the disk while it's being written.
+pub fn lock_output(path: &Path, output: &File, system: &System) -> Result<Lock> {
+    let metadata = output.metadata()?;
+    let disk = if metadata.file_type().is_block_device() {
+        system
+            .whole_disk(metadata.rdev())?
+            .map(|name| system.dev_root.join(name))
+    } else {
+        None
+    };
+    let mut file = match &disk {
+        Some(disk) => {
+            File::open(disk).with_context(|| format!("Failed to open {}", disk.display()))?
+        }
+        None => output.try_clone()?,
+    };
+
+    let deadline = Instant::now() + LOCK_TIMEOUT;
+    loop {
+        match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(flock) => return Ok(Lock { _flock: flock }),
+            Err((f, Errno::EWOULDBLOCK)) if Instant::now() < deadline => {
+                file = f;
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err((f, Errno::EWOULDBLOCK)) => {
+                let locked = f.metadata()?;
+                let holder = system
+                    .lock_holder(locked.dev(), locked.ino())
+                    .ok()
+                    .flatten()
+                    .map_or_else(|| "another process".to_string(), |p| p.to_string());
+                match disk {
+                    Some(disk) => bail!(
+                        "{} is busy: {} is locked by {}",
+                        path.display(),
+                        disk.display(),
+                        holder
+                    ),
+                    None => bail!("{} is busy: locked by {}", path.display(), holder),
+                }
+            }
+            Err((_, e)) => {
+                return Err(e).with_context(|| format!("Failed to lock {}", path.display()));
+            }
+        }
+    }
+}
+
+/// How the content of ranges is dropped from the destination
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DiscardMethod {
+    /// BLKDISCARD on a block device, after which the ranges may read back anything
+    Discard,
+    /// BLKZEROOUT on a block device
+    ZeroOut,
+    /// fallocate(PUNCH_HOLE) on a regular file
+    PunchHole,
+}
+
+impl DiscardMethod {
+    /// Pick the discard method matching the type of the destination. With `zeroes` set the
+    /// ranges have to read back zeroes afterwards, which discarding doesn't guarantee
+    pub fn for_output(metadata: &Metadata, system: &System, zeroes: bool) -> Result<Self> {
+        let file_type = metadata.file_type();
+        if file_type.is_file() {
+            Ok(DiscardMethod::PunchHole)
+        } else if file_type.is_block_device() {
+            Self::for_block_device(system, metadata.rdev(), zeroes)
+        } else {
+            bail!("Discarding is only supported on block devices and regular files")
+        }
+    }
+
+    /// Discard on block devices whose queue supports it, unless zeroes are needed
+    fn for_block_device(system: &System, rdev: u64, zeroes: bool) -> Result<Self> {
+        if !zeroes && system.discard_max_bytes(rdev)? > 0 {
+            Ok(DiscardMethod::Discard)
+        } else {
+            Ok(DiscardMethod::ZeroOut)
+        }
+    }
+}
+
+fn logical_block_size<T: AsFd>(output: &T) -> Result<u64> {
+    let mut size: c_int = 0;
+    // SAFETY: BLKSSZGET only writes a single int
+    unsafe { blksszget(output.as_fd().as_raw_fd(), &mut size) }
+        .context("Failed to query logical block size")?;
+    Ok(size as u64)
+}
+
+/// Drop the content of the given ranges on the destination, returning the number of bytes
+/// discarded.
+///
+/// Ranges on block devices are shrunk to the device logical block size as required by the
+/// kernel, with zeroes written over the partial blocks left at their edges. If the device
+/// turns out not to support discarding after all, zeroes are written out instead.
+pub fn discard_ranges<T, I>(output: &T, method: DiscardMethod, ranges: I) -> Result<u64>
+where
+    T: AsFd,
+    I: IntoIterator<Item = Range<u64>>,
+{
+    let fd = output.as_fd();
+    let align = match method {
+        DiscardMethod::PunchHole => 1,
+        _ => logical_block_size(&fd)?,
+    };
+
+    let file = File::from(fd.try_clone_to_owned()?);
+    let mut method = method;
+    let mut discarded = 0;
+    for range in ranges {
+        let start = range.start.next_multiple_of(align).min(range.end);
+        let end = (range.end - range.end % align).max(start);
+        write_zeroes(&file, range.start..start)?;
+        write_zeroes(&file, end..range.end)?;
+        if start == end {
+            continue;
+        }
+
+        let span = [start, end - start];
+        let r = match method {
+            DiscardMethod::PunchHole => fallocate(
+                fd,
+                FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+                start as i64,
+                (end - start) as i64,
+            ),
+            // SAFETY: Both ioctls only read the two u64 values of span
+            DiscardMethod::Discard => unsafe { blkdiscard(fd.as_raw_fd(), &span) }.map(|_| ()),
+            DiscardMethod::ZeroOut => unsafe { blkzeroout(fd.as_raw_fd(), &span) }.map(|_| ()),
+        };
+        match r {
+            Ok(()) => (),
+            Err(Errno::EOPNOTSUPP) if method == DiscardMethod::Discard => {
+                method = DiscardMethod::ZeroOut;
+                // SAFETY: See above
+                unsafe { blkzeroout(fd.as_raw_fd(), &span) }
+                    .with_context(|| format!("Failed to zero out {start}..{end}"))?;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to discard {start}..{end}"));
+            }
+        }
+        discarded += end - start;
+    }
+
+    Ok(discarded)
+}
+
+fn write_zeroes(file: &File, range: Range<u64>) -> Result<()> {
+    if range.is_empty() {
+        return Ok(());
+    }
+    let zeroes = vec![0; (range.end - range.start) as usize];
+    file.write_all_at(&zeroes, range.start)
+        .with_context(|| format!("Failed to zero {}..{}", range.start, range.end))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::sys::stat::makedev;
+    use std::path::Path;
+
+    #[test]
+    fn discard_method() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/system");
+        let system = System {
+            proc_root: root.join("proc"),
+            sys_root: root.join("sys"),
+            dev_root: root.join("dev"),
+        };
+        let method = |rdev, zeroes| DiscardMethod::for_block_device(&system, rdev, zeroes).unwrap();
+        // Partitions go by the queue of their disk
+        assert_eq!(DiscardMethod::Discard, method(makedev(8, 0), false));
+        assert_eq!(DiscardMethod::Discard, method(makedev(8, 1), false));
+        assert_eq!(DiscardMethod::ZeroOut, method(makedev(8, 0), true));
+        // No discard support
+        assert_eq!(DiscardMethod::ZeroOut, method(makedev(8, 16), false));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::device::Lock;
+use crate::throttle::{BdiTuning, DirtyLimit, Throttled};
+use crate::{
+    Copy, Decoder, Image, arrange_writes, check_free_space, device, discard_unmapped,
+    finish_nobmap, fit_image, fixup_partition_table, open_output, restrict_input,
+    setup_copy_options, setup_nobmap_options, setup_output, setup_progress_bar, setup_remote_input,
+    setup_spinner, setup_sync_input, setup_throttling, sync_output, truncate_output,
+    write_alignment,
+};
+use anyhow::{Result, bail};
+use async_compression::futures::bufread::GzipDecoder;
+use bmap_parser::{Bmap, CopyError, CopyReport, Discarder, FanOut, Partition, SparseMap};
+use futures::TryStreamExt;
+use indicatif::MultiProgress;
+use std::fs::{File, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::io::SyncIoBridge;
+
+struct Destination {
+    path: PathBuf,
+    file: File,
+    metadata: Metadata,
+    limit: Option<DirtyLimit>,
+    _tuning: Option<BdiTuning>,
+    _lock: Lock,
+}
+
+/// Copy the image to all destinations at once, decoding and verifying the input only once
+pub(crate) async fn copy(
+    c: &Copy,
+    bmap: Option<Bmap>,
+    partition: Option<&Partition>,
+) -> Result<Option<CopyReport>> {
+    let input = match &c.image {
+        Image::Url(url) => {
+            let res = setup_remote_input(url.clone()).await?;
+            let stream = res
+                .bytes_stream()
+                .map_err(std::io::Error::other)
+                .into_async_read();
+            let reader = GzipDecoder::new(stream).compat();
+            Decoder::new(Discarder::new(SyncIoBridge::new(reader)))
+        }
+        image => setup_sync_input(image)?,
+    };
+
+    tokio::task::block_in_place(|| {
+        // Without a bmap the input itself gets limited to the partition. Seeking blocks on remote
+        // input, so this has to happen here
+        let input = match bmap {
+            Some(_) => input,
+            None => restrict_input(input, partition)?,
+        };
+        copy_sync(input, bmap, c)
+    })
+}
+
+/// Destinations which failed, with the reason
+type Failures = Vec<(PathBuf, anyhow::Error)>;
+
+/// Run a setup step for each destination, moving those for which it fails to the failures so the
+/// others can carry on
+fn setup_each<T>(
+    destinations: &mut Vec<Destination>,
+    failures: &mut Failures,
+    mut f: impl FnMut(&Destination) -> Result<T>,
+) -> Vec<T> {
+    let mut results = Vec::new();
+    let mut kept = Vec::new();
+    for d in destinations.drain(..) {
+        match f(&d) {
+            Ok(r) => {
+                results.push(r);
+                kept.push(d);
+            }
+            Err(e) => failures.push((d.path.clone(), e)),
+        }
+    }
+    *destinations = kept;
+    results
+}
+
+fn open_destination(path: &Path, c: &Copy) -> Result<Destination> {
+    let (file, lock) = open_output(path, c)?;
+    let (limit, tuning) = setup_throttling(&file, c)?;
+    let metadata = file.metadata()?;
+    Ok(Destination {
+        path: path.to_path_buf(),
+        file,
+        metadata,
+        limit,
+        _tuning: tuning,
+        _lock: lock,
+    })
+}
+
+fn copy_sync(mut input: Decoder, bmap: Option<Bmap>, c: &Copy) -> Result<Option<CopyReport>> {
+    let mut destinations = Vec::new();
+    let mut failures = Failures::new();
+    for path in c.dest.iter() {
+        match open_destination(path, c) {
+            Ok(d) => destinations.push(d),
+            Err(e) => failures.push((path.clone(), e)),
+        }
+    }
+    let sizes = setup_each(&mut destinations, &mut failures, |d| {
+        let size = device::fixed_size(&d.file, &d.metadata)?;
+        if let Some(bmap) = &bmap {
+            check_free_space(&d.file, &d.metadata, bmap, c)?;
+            fit_image(bmap.clone(), size, c.dest_offset, c.force)?;
+        }
+        Ok(size)
+    });
+    // The image has to fit on the smallest destination
+    let dest_size = sizes.into_iter().flatten().min();
+    let alignments = setup_each(&mut destinations, &mut failures, |d| {
+        write_alignment(&d.metadata, c)
+    });
+    let mut alignment = alignments.first().copied().flatten();
+    if alignments.iter().any(|a| *a != alignment) {
+        println!("Destinations need different write alignments, not aligning writes");
+        alignment = None;
+    }
+    let bmap = bmap
+        .map(|b| {
+            Ok::<_, anyhow::Error>(arrange_writes(
+                fit_image(b, dest_size, c.dest_offset, c.force)?,
+                alignment,
+                c,
+            ))
+        })
+        .transpose()?;
+    let bmap = bmap.as_ref();
+    setup_each(&mut destinations, &mut failures, |d| match bmap {
+        Some(bmap) => setup_output(&d.file, bmap, c, d.metadata.clone()),
+        None => truncate_output(&d.file, &d.metadata, c),
+    });
+    if destinations.is_empty() {
+        print_outcomes(Vec::new(), failures, c);
+        bail!("All {} destinations failed", c.dest.len());
+    }
+
+    let mp = MultiProgress::new();
+    let pbs: Vec<_> = destinations
+        .iter()
+        .map(|d| {
+            let pb = match bmap {
+                Some(bmap) => setup_progress_bar(bmap, c),
+                None => setup_spinner(),
+            };
+            pb.set_prefix(format!("{} ", d.path.display()));
+            mp.add(pb)
+        })
+        .collect();
+    let writers = destinations
+        .iter_mut()
+        .zip(pbs.iter())
+        .map(|(d, pb)| Ok(pb.wrap_write(Throttled::new(d.file.try_clone()?, d.limit.take()))))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut fanout = FanOut::new(writers);
+    let copied = match bmap {
+        Some(bmap) => {
+            let options = setup_copy_options(c, dest_size, &pbs);
+            bmap_parser::copy_with_options(&mut input, &mut fanout, bmap, &options)
+                .map(|report| (Some(report), None))
+        }
+        None => {
+            let metadata: Vec<_> = destinations.iter().map(|d| d.metadata.clone()).collect();
+            let options = setup_nobmap_options(c, dest_size, &metadata);
+            bmap_parser::copy_nobmap_with_options(&mut input, &mut fanout, &options)
+                .map(|map| (None, Some(map)))
+        }
+    };
+    let results = fanout.finish();
+    for pb in pbs {
+        pb.finish_and_clear();
+    }
+
+    let (mut report, map) = match copied {
+        Ok(copied) => copied,
+        // Each destination reports its own write failure below
+        Err(CopyError::WriteError(_)) => (None, None),
+        Err(e) => return Err(e.into()),
+    };
+
+    println!("Done: Syncing...");
+    let syncing = Instant::now();
+    let mut outcomes = Vec::new();
+    for (d, (_, result)) in destinations.into_iter().zip(results) {
+        let result = result
+            .map_err(anyhow::Error::from)
+            .and_then(|_| finish_destination(&d, bmap, map.as_ref(), c));
+        match result {
+            Ok(()) => outcomes.push(d.path),
+            Err(e) => failures.push((d.path, e)),
+        }
+    }
+    if let Some(report) = &mut report {
+        report.add_sync_time(syncing.elapsed());
+    }
+
+    let failed = failures.len();
+    print_outcomes(outcomes, failures, c);
+    if failed > 0 {
+        bail!("{} of {} destinations failed", failed, c.dest.len());
+    }
+    Ok(report)
+}
+
+/// Show whether each destination got written, in the order they were given
+fn print_outcomes(succeeded: Vec<PathBuf>, failures: Failures, c: &Copy) {
+    let mut outcomes: Vec<_> = succeeded
+        .into_iter()
+        .map(|path| (path, None))
+        .chain(failures.into_iter().map(|(path, e)| (path, Some(e))))
+        .collect();
+    outcomes.sort_by_key(|(path, _)| c.dest.iter().position(|p| p == path));
+    for (path, error) in outcomes {
+        match error {
+            None => println!("{}: OK", path.display()),
+            Some(e) => println!("{}: Failed: {:#}", path.display(), e),
+        }
+    }
+}
+
+fn finish_destination(
+    d: &Destination,
+    bmap: Option<&Bmap>,
+    map: Option<&SparseMap>,
+    c: &Copy,
+) -> Result<()> {
+    match (bmap, map) {
+        (Some(bmap), _) if c.discard => discard_unmapped(&d.file, bmap, c, d.metadata.clone())?,
+        (_, Some(map)) => finish_nobmap(&d.file, map, c, d.metadata.clone())?,
+        _ => (),
+    }
+    fixup_partition_table(&d.file, c)?;
+    sync_output(&d.file)?;
+    Ok(())
+}
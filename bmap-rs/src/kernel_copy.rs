@@ -0,0 +1,140 @@
+use anyhow::{Context, Result, bail};
+use bmap_parser::Bmap;
+use nix::errno::Errno;
+use nix::fcntl::copy_file_range;
+use nix::ioctl_write_ptr;
+use nix::libc::file_clone_range;
+use std::fs::File;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::time::{Duration, Instant};
+
+ioctl_write_ptr!(ficlonerange, 0x94, 13, file_clone_range);
+
+/// Bytes copied by each method
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KernelCopy {
+    pub cloned: u64,
+    pub copied: u64,
+    /// Bytes of the output left alone between the ranges
+    pub skipped: u64,
+    pub elapsed: Duration,
+}
+
+/// Copy the data written for the image from an uncompressed image file into a regular file
+/// without passing it through userspace, leaving holes untouched. Ranges get cloned where the
+/// filesystem can share extents between the files, otherwise copied by copy_file_range. The
+/// data isn't verified, which has to happen beforehand.
+pub fn copy_ranges(
+    input: &File,
+    output: &File,
+    bmap: &Bmap,
+    dest_offset: u64,
+) -> Result<KernelCopy> {
+    let started = Instant::now();
+    let mut result = KernelCopy::default();
+    let mut clone = true;
+    let mut copy = true;
+    let mut position = 0;
+    for range in bmap.block_map() {
+        let written = range.write_range();
+        result.skipped += written.start - position;
+        position = written.end;
+        let src = bmap.source_offset() + written.start;
+        let dest = dest_offset + written.start;
+        let len = written.end - written.start;
+
+        if clone {
+            let args = file_clone_range {
+                src_fd: input.as_raw_fd().into(),
+                src_offset: src,
+                src_length: len,
+                dest_offset: dest,
+            };
+            // SAFETY: FICLONERANGE only reads the argument struct
+            match unsafe { ficlonerange(output.as_raw_fd(), &args) } {
+                Ok(_) => {
+                    result.cloned += len;
+                    continue;
+                }
+                // Ranges not aligned to the filesystem block size can't be cloned
+                Err(Errno::EINVAL) => (),
+                Err(_) => clone = false,
+            }
+        }
+
+        let mut done = 0;
+        while copy && done < len {
+            let mut off_in = (src + done) as i64;
+            let mut off_out = (dest + done) as i64;
+            let todo = (len - done).min(1 << 30) as usize;
+            match copy_file_range(input, Some(&mut off_in), output, Some(&mut off_out), todo) {
+                Ok(0) => bail!("Unexpected end of image at offset {}", src + done),
+                Ok(n) => done += n as u64,
+                // Not supported for these files, e.g. across filesystems on older kernels
+                Err(Errno::EXDEV | Errno::ENOSYS | Errno::EOPNOTSUPP | Errno::EINVAL) => {
+                    copy = false
+                }
+                Err(e) => return Err(e).context("Failed to copy image data"),
+            }
+        }
+        copy_userspace(input, output, src + done, dest + done, len - done)?;
+        result.copied += len;
+    }
+    result.elapsed = started.elapsed();
+    Ok(result)
+}
+
+fn copy_userspace(input: &File, output: &File, src: u64, dest: u64, len: u64) -> Result<()> {
+    let mut buf = vec![0; len.min(8 * 1024 * 1024) as usize];
+    let mut done = 0;
+    while done < len {
+        let todo = (len - done).min(buf.len() as u64) as usize;
+        input
+            .read_exact_at(&mut buf[..todo], src + done)
+            .context("Failed to read image data")?;
+        output
+            .write_all_at(&buf[..todo], dest + done)
+            .context("Failed to write image data")?;
+        done += todo as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bmap_parser::{HashType, HashValue};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn copy_ranges() {
+        let dir = TempDir::new().unwrap();
+        let dir = dir.path();
+        let data: Vec<u8> = (0..16 * 4096).map(|i| (i % 251) as u8 | 1).collect();
+        fs::write(dir.join("image"), &data).unwrap();
+
+        let mut builder = Bmap::builder();
+        builder
+            .image_size(data.len() as u64)
+            .block_size(4096)
+            .blocks(16)
+            .mapped_blocks(3)
+            .checksum_type(HashType::Sha256)
+            .add_byte_range(4096, 4096, HashValue::Sha256([0; 32]))
+            .add_byte_range(10 * 4096, 2 * 4096, HashValue::Sha256([0; 32]));
+        let bmap = builder.build().unwrap();
+
+        let input = File::open(dir.join("image")).unwrap();
+        let output = File::create(dir.join("output")).unwrap();
+        let copied = super::copy_ranges(&input, &output, &bmap, 4096).unwrap();
+        assert_eq!(3 * 4096, copied.cloned + copied.copied);
+        assert_eq!(9 * 4096, copied.skipped);
+
+        let mut expected = vec![0; 13 * 4096];
+        expected[2 * 4096..3 * 4096].copy_from_slice(&data[4096..2 * 4096]);
+        expected[11 * 4096..13 * 4096].copy_from_slice(&data[10 * 4096..12 * 4096]);
+        assert_eq!(expected, fs::read(dir.join("output")).unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+use crate::blockdev::System;
+use anyhow::Result;
+use indicatif::HumanBytes;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub(crate) struct ListDevices {
+    pub(crate) json: bool,
+    pub(crate) sysfs_root: PathBuf,
+    pub(crate) proc_root: PathBuf,
+    pub(crate) dev_root: PathBuf,
+}
+
+fn yes_no(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+/// List the disks of the system, marking removable and USB ones as likely targets
+pub(crate) fn list_devices(l: &ListDevices) -> Result<()> {
+    let system = System {
+        proc_root: l.proc_root.clone(),
+        sys_root: l.sysfs_root.clone(),
+        dev_root: l.dev_root.clone(),
+    };
+    let disks = system.disks()?;
+
+    if l.json {
+        println!("{}", serde_json::to_string_pretty(&disks)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<14} {:>11} {:<9} {:<4} {:<28} {:<22} MOUNTS",
+        "DEVICE", "SIZE", "REMOVABLE", "USB", "MODEL", "SERIAL"
+    );
+    for disk in disks.iter() {
+        let mounts: Vec<_> = disk
+            .mounts
+            .iter()
+            .map(|m| format!("{} on {}", m.device, m.target))
+            .collect();
+        println!(
+            "{:<14} {:>11} {:<9} {:<4} {:<28} {:<22} {}",
+            disk.path.display(),
+            HumanBytes(disk.size).to_string(),
+            yes_no(disk.removable),
+            yes_no(disk.usb),
+            disk.description(),
+            disk.serial.as_deref().unwrap_or("-"),
+            mounts.join(", ")
+        );
+    }
+    Ok(())
+}
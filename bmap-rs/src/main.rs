@@ -1,37 +1,150 @@
+mod bad_blocks;
+mod blockdev;
+mod bmap_file;
+mod device;
+mod fanout;
+mod kernel_copy;
+mod list;
+mod sums;
+mod throttle;
+
 use anyhow::{Context, Result, anyhow, bail, ensure};
 use async_compression::futures::bufread::GzipDecoder;
-use bmap_parser::{AsyncDiscarder, Bmap, Discarder, SeekForward};
-use clap::{Arg, ArgAction, Command, arg, command};
+use blockdev::DiskMatch;
+use bmap_parser::{
+    AsyncDiscarder, AsyncSeekForward, Bmap, CopyOptions, CopyReport, Discarder, Partition,
+    PartitionTable, SeekForward, SparseMap,
+};
+use clap::{Arg, ArgAction, Command, arg, command, value_parser};
+use device::{DiscardMethod, Lock};
 use flate2::read::GzDecoder;
-use futures::TryStreamExt;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use futures::{AsyncReadExt, TryStreamExt};
+use indicatif::{HumanBytes, ProgressBar, ProgressState, ProgressStyle};
+use kernel_copy::KernelCopy;
+use list::ListDevices;
+use nix::sys::stat::fstat;
 use nix::unistd::ftruncate;
 use reqwest::{Response, Url};
 use std::ffi::OsStr;
 use std::fmt::Write;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsFd;
 use std::path::{Path, PathBuf};
-use tokio_util::compat::TokioAsyncReadCompatExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use throttle::{BdiTuning, DirtyLimit, Throttled};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use tokio_util::io::SyncIoBridge;
+
+/// Destination standing for stdout
+const STDOUT: &str = "-";
 
 #[derive(Debug)]
 enum Image {
     Path(PathBuf),
     Url(Url),
+    /// Given as `-`
+    Stdin,
+}
+
+/// Unit to line writes up with on the destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WriteAlignment {
+    /// Detected from the destination
+    Auto,
+    Bytes(u64),
+}
+
+fn parse_alignment(s: &str) -> Result<WriteAlignment, String> {
+    match s {
+        "auto" => Ok(WriteAlignment::Auto),
+        _ => s
+            .parse()
+            .map(WriteAlignment::Bytes)
+            .map_err(|_| format!("expected auto or a number of bytes, got {s}")),
+    }
+}
+
+/// Rates to limit copying to in bytes per second, 0 meaning no limit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct RateLimit {
+    input: u64,
+    output: u64,
+}
+
+impl RateLimit {
+    /// Combine with a later given limit, which takes precedence where set
+    fn merge(self, later: &RateLimit) -> Self {
+        let pick = |earlier, later| if later > 0 { later } else { earlier };
+        Self {
+            input: pick(self.input, later.input),
+            output: pick(self.output, later.output),
+        }
+    }
+}
+
+fn parse_rate(s: &str) -> Result<RateLimit, String> {
+    let parse = |rate: &str| {
+        rate.parse()
+            .map_err(|_| format!("expected [input=|output=]BYTES, got {s}"))
+    };
+    match s.split_once('=') {
+        Some(("input", rate)) => Ok(RateLimit {
+            input: parse(rate)?,
+            output: 0,
+        }),
+        Some(("output", rate)) => Ok(RateLimit {
+            input: 0,
+            output: parse(rate)?,
+        }),
+        Some(_) => Err(format!("expected [input=|output=]BYTES, got {s}")),
+        None => parse(s).map(|rate| RateLimit {
+            input: rate,
+            output: rate,
+        }),
+    }
 }
 
 #[derive(Debug)]
 struct Copy {
     image: Image,
-    dest: PathBuf,
+    bmap: Option<String>,
+    dest: Vec<PathBuf>,
+    /// Standard output, once taken over for writing the image to a `-` destination
+    stdout: Option<File>,
     nobmap: bool,
+    discard: bool,
+    zero_holes: bool,
+    dest_offset: u64,
+    partition: Option<String>,
+    relocate_gpt: bool,
+    grow_last_partition: bool,
+    force: bool,
+    dest_match: DiskMatch,
+    expect_size: Option<u64>,
+    yes: bool,
+    reread_partitions: bool,
+    flush_cache: bool,
+    eject: bool,
+    power_off: bool,
+    dirty_limit: u64,
+    tune_bdi: bool,
+    align_writes: Option<WriteAlignment>,
+    coalesce: u64,
+    image_digest: bool,
+    sha256sums: Option<String>,
+    continue_on_error: bool,
+    error_report: Option<PathBuf>,
+    limit_rate: RateLimit,
 }
 
 #[derive(Debug)]
 
 enum Subcommand {
-    Copy(Copy),
+    Copy(Box<Copy>),
+    ListDevices(ListDevices),
 }
 
 #[derive(Debug)]
@@ -49,28 +162,302 @@ impl Opts {
                 Command::new("copy")
                     .about("Copy image to block device or file")
                     .arg(arg!([IMAGE]).required(true))
-                    .arg(arg!([DESTINATION]).required(true))
+                    .arg(arg!([DESTINATION]...).required_unless_present_any([
+                        "dest-serial",
+                        "dest-id",
+                        "dest-model",
+                    ]))
+                    .arg(
+                        Arg::new("bmap")
+                            .long("bmap")
+                            .value_name("PATH|URL")
+                            .help("Use this bmap file rather than looking for one next to the image, required when reading the image from stdin")
+                            .conflicts_with("nobmap"),
+                    )
                     .arg(
                         Arg::new("nobmap")
                             .short('n')
                             .long("nobmap")
                             .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("discard")
+                            .long("discard")
+                            .help("Discard or punch holes in unmapped ranges of the destination")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("zero-holes")
+                            .long("zero-holes")
+                            .help("Write zeroes over unmapped ranges of the destination")
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with_all(["nobmap", "discard"]),
+                    )
+                    .arg(
+                        Arg::new("dest-offset")
+                            .long("dest-offset")
+                            .value_name("BYTES")
+                            .help("Offset in the destination to write the image at")
+                            .value_parser(value_parser!(u64))
+                            .default_value("0"),
+                    )
+                    .arg(
+                        Arg::new("partition")
+                            .long("partition")
+                            .value_name("NUMBER|LABEL")
+                            .help("Only copy a single partition of the image"),
+                    )
+                    .arg(
+                        Arg::new("relocate-gpt")
+                            .long("relocate-gpt")
+                            .help("Move the GPT backup header to the end of the destination")
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with_all(["dest-offset", "partition"]),
+                    )
+                    .arg(
+                        Arg::new("grow-last-partition")
+                            .long("grow-last-partition")
+                            .help("Extend the last partition to the end of the destination")
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with_all(["dest-offset", "partition"]),
+                    )
+                    .arg(
+                        Arg::new("force")
+                            .long("force")
+                            .help(
+                                "Copy even if the destination is in use or too small for the image",
+                            )
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("dest-serial")
+                            .long("dest-serial")
+                            .value_name("SERIAL")
+                            .help("Write to the disk with this serial number")
+                            .conflicts_with("DESTINATION"),
+                    )
+                    .arg(
+                        Arg::new("dest-id")
+                            .long("dest-id")
+                            .value_name("ID")
+                            .help("Write to the disk with this name in /dev/disk/by-id")
+                            .conflicts_with("DESTINATION"),
+                    )
+                    .arg(
+                        Arg::new("dest-model")
+                            .long("dest-model")
+                            .value_name("MODEL")
+                            .help("Write to the disk of this model")
+                            .conflicts_with("DESTINATION"),
+                    )
+                    .arg(
+                        Arg::new("expect-size")
+                            .long("expect-size")
+                            .value_name("BYTES")
+                            .help("Refuse block devices which aren't exactly this size")
+                            .value_parser(value_parser!(u64)),
+                    )
+                    .arg(
+                        Arg::new("yes")
+                            .short('y')
+                            .long("yes")
+                            .help("Overwrite block devices without asking for confirmation")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("reread-partitions")
+                            .long("reread-partitions")
+                            .help("Have the kernel re-read the partition table after copying")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("flush-cache")
+                            .long("flush-cache")
+                            .help("Flush the write cache of the destination after copying")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("eject")
+                            .long("eject")
+                            .help("Detach the destination disk from the system after copying")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("power-off")
+                            .long("power-off")
+                            .help("Eject the destination and power off its USB port after copying")
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with("eject"),
+                    )
+                    .arg(
+                        Arg::new("dirty-limit")
+                            .long("dirty-limit")
+                            .value_name("BYTES")
+                            .help("Write back block device data every BYTES rather than only at the end, 0 to disable")
+                            .value_parser(value_parser!(u64))
+                            .default_value("67108864"),
+                    )
+                    .arg(
+                        Arg::new("tune-bdi")
+                            .long("tune-bdi")
+                            .help("Limit the page cache use of the destination through sysfs while copying")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("align-writes")
+                            .long("align-writes")
+                            .value_name("auto|BYTES")
+                            .help("Pad writes to line up with the erase blocks of the destination, detected from sysfs or given in bytes")
+                            .value_parser(parse_alignment)
+                            .conflicts_with("nobmap"),
+                    )
+                    .arg(
+                        Arg::new("coalesce")
+                            .long("coalesce")
+                            .value_name("BYTES")
+                            .help("Copy gaps smaller than BYTES between mapped ranges along rather than seeking over them")
+                            .value_parser(value_parser!(u64))
+                            .default_value("0")
+                            .conflicts_with("nobmap"),
+                    )
+                    .arg(
+                        Arg::new("image-digest")
+                            .long("image-digest")
+                            .help("Compute the SHA-256 digest of the whole uncompressed image while copying")
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with("nobmap"),
+                    )
+                    .arg(
+                        Arg::new("sha256sums")
+                            .long("sha256sums")
+                            .value_name("PATH|URL")
+                            .help("Check the digest of the whole uncompressed image against a SHA256SUMS file")
+                            .conflicts_with_all(["nobmap", "partition"]),
+                    )
+                    .arg(
+                        Arg::new("continue-on-error")
+                            .long("continue-on-error")
+                            .help("Skip blocks which can't be written and ranges failing verification rather than stopping")
+                            .action(ArgAction::SetTrue)
+                            .conflicts_with("nobmap"),
+                    )
+                    .arg(
+                        Arg::new("error-report")
+                            .long("error-report")
+                            .value_name("PATH")
+                            .help("Write the bad blocks and unverified ranges as JSON to PATH")
+                            .value_parser(value_parser!(PathBuf))
+                            .requires("continue-on-error"),
+                    )
+                    .arg(
+                        Arg::new("limit-rate")
+                            .long("limit-rate")
+                            .value_name("[input=|output=]BYTES")
+                            .help("Limit reading the image and writing the destinations to BYTES per second, or only either of them")
+                            .value_parser(parse_rate)
+                            .action(ArgAction::Append),
+                    ),
+            )
+            .subcommand(
+                Command::new("list-devices")
+                    .about("List disks which could be written to")
+                    .arg(
+                        Arg::new("json")
+                            .long("json")
+                            .help("Print the devices as JSON")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("sysfs-root")
+                            .long("sysfs-root")
+                            .value_name("PATH")
+                            .help("Where sysfs is mounted")
+                            .value_parser(value_parser!(PathBuf))
+                            .default_value("/sys"),
+                    )
+                    .arg(
+                        Arg::new("proc-root")
+                            .long("proc-root")
+                            .value_name("PATH")
+                            .help("Where procfs is mounted, to look up mounts in")
+                            .value_parser(value_parser!(PathBuf))
+                            .default_value("/proc"),
+                    )
+                    .arg(
+                        Arg::new("dev-root")
+                            .long("dev-root")
+                            .value_name("PATH")
+                            .help("Where the device nodes are")
+                            .value_parser(value_parser!(PathBuf))
+                            .default_value("/dev"),
                     ),
             )
             .get_matches();
         match matches.subcommand() {
             Some(("copy", sub_matches)) => Opts {
-                command: Subcommand::Copy({
+                command: Subcommand::Copy(Box::new({
                     Copy {
-                        image: match Url::parse(sub_matches.get_one::<String>("IMAGE").unwrap()) {
-                            Ok(url) => Image::Url(url),
-                            Err(_) => Image::Path(PathBuf::from(
-                                sub_matches.get_one::<String>("IMAGE").unwrap(),
-                            )),
+                        image: match sub_matches.get_one::<String>("IMAGE").unwrap().as_str() {
+                            "-" => Image::Stdin,
+                            image => match Url::parse(image) {
+                                Ok(url) => Image::Url(url),
+                                Err(_) => Image::Path(PathBuf::from(image)),
+                            },
                         },
-                        dest: PathBuf::from(sub_matches.get_one::<String>("DESTINATION").unwrap()),
+                        bmap: sub_matches.get_one::<String>("bmap").cloned(),
+                        dest: sub_matches
+                            .get_many::<String>("DESTINATION")
+                            .into_iter()
+                            .flatten()
+                            .map(PathBuf::from)
+                            .collect(),
+                        stdout: None,
                         nobmap: sub_matches.get_flag("nobmap"),
+                        discard: sub_matches.get_flag("discard"),
+                        zero_holes: sub_matches.get_flag("zero-holes"),
+                        dest_offset: *sub_matches.get_one::<u64>("dest-offset").unwrap(),
+                        partition: sub_matches.get_one::<String>("partition").cloned(),
+                        relocate_gpt: sub_matches.get_flag("relocate-gpt"),
+                        grow_last_partition: sub_matches.get_flag("grow-last-partition"),
+                        force: sub_matches.get_flag("force"),
+                        dest_match: DiskMatch {
+                            serial: sub_matches.get_one::<String>("dest-serial").cloned(),
+                            id: sub_matches.get_one::<String>("dest-id").cloned(),
+                            model: sub_matches.get_one::<String>("dest-model").cloned(),
+                        },
+                        expect_size: sub_matches.get_one::<u64>("expect-size").copied(),
+                        yes: sub_matches.get_flag("yes"),
+                        reread_partitions: sub_matches.get_flag("reread-partitions"),
+                        flush_cache: sub_matches.get_flag("flush-cache"),
+                        eject: sub_matches.get_flag("eject"),
+                        power_off: sub_matches.get_flag("power-off"),
+                        dirty_limit: *sub_matches.get_one::<u64>("dirty-limit").unwrap(),
+                        tune_bdi: sub_matches.get_flag("tune-bdi"),
+                        align_writes: sub_matches
+                            .get_one::<WriteAlignment>("align-writes")
+                            .copied(),
+                        coalesce: *sub_matches.get_one::<u64>("coalesce").unwrap(),
+                        image_digest: sub_matches.get_flag("image-digest"),
+                        sha256sums: sub_matches.get_one::<String>("sha256sums").cloned(),
+                        continue_on_error: sub_matches.get_flag("continue-on-error"),
+                        error_report: sub_matches.get_one::<PathBuf>("error-report").cloned(),
+                        limit_rate: sub_matches
+                            .get_many::<RateLimit>("limit-rate")
+                            .into_iter()
+                            .flatten()
+                            .fold(RateLimit::default(), |limit, rate| limit.merge(rate)),
                     }
+                })),
+            },
+            Some(("list-devices", sub_matches)) => Opts {
+                command: Subcommand::ListDevices(ListDevices {
+                    json: sub_matches.get_flag("json"),
+                    sysfs_root: sub_matches
+                        .get_one::<PathBuf>("sysfs-root")
+                        .unwrap()
+                        .clone(),
+                    proc_root: sub_matches.get_one::<PathBuf>("proc-root").unwrap().clone(),
+                    dev_root: sub_matches.get_one::<PathBuf>("dev-root").unwrap().clone(),
                 }),
             },
             _ => unreachable!(
@@ -80,44 +467,15 @@ impl Opts {
     }
 }
 
-fn append(path: PathBuf) -> PathBuf {
-    let mut p = path.into_os_string();
-    p.push(".bmap");
-    p.into()
-}
-
-fn find_bmap(img: &Path) -> Option<PathBuf> {
-    let mut bmap = img.to_path_buf();
-    loop {
-        bmap = append(bmap);
-        if bmap.exists() {
-            return Some(bmap);
-        }
-
-        // Drop .bmap
-        bmap.set_extension("");
-        bmap.extension()?;
-        // Drop existing orignal extension part
-        bmap.set_extension("");
-    }
-}
-
-fn find_remote_bmap(mut url: Url) -> Result<Url> {
-    let mut path = PathBuf::from(url.path());
-    path.set_extension("bmap");
-    url.set_path(path.to_str().unwrap());
-    Ok(url)
-}
-
 trait ReadSeekForward: SeekForward + Read {}
 impl<T: Read + SeekForward> ReadSeekForward for T {}
 
 struct Decoder {
-    inner: Box<dyn ReadSeekForward>,
+    inner: Box<dyn ReadSeekForward + Send>,
 }
 
 impl Decoder {
-    fn new<T: ReadSeekForward + 'static>(inner: T) -> Self {
+    fn new<T: ReadSeekForward + Send + 'static>(inner: T) -> Self {
         Self {
             inner: Box::new(inner),
         }
@@ -147,6 +505,38 @@ fn setup_local_input(path: &Path) -> Result<Decoder> {
     }
 }
 
+/// Read the image from stdin, decompressing it when it starts like a gzip stream
+fn setup_stdin_input() -> Result<Decoder> {
+    let mut stdin = BufReader::new(io::stdin());
+    if stdin.fill_buf()?.starts_with(&bmap_file::GZIP_MAGIC) {
+        let gz = flate2::bufread::GzDecoder::new(stdin);
+        Ok(Decoder::new(Discarder::new(gz)))
+    } else {
+        Ok(Decoder::new(Discarder::new(stdin)))
+    }
+}
+
+/// Open an image which is read synchronously
+fn setup_sync_input(image: &Image) -> Result<Decoder> {
+    match image {
+        Image::Path(path) => {
+            ensure!(path.exists(), "Image file doesn't exist");
+            setup_local_input(path)
+        }
+        Image::Stdin => setup_stdin_input(),
+        Image::Url(_) => unreachable!("Remote images are read asynchronously"),
+    }
+}
+
+/// Whether seeking forward in the image reads through the data in between rather than skipping
+/// it, which is the case for all but uncompressed local files
+fn reads_seeks(image: &Image) -> bool {
+    match image {
+        Image::Path(path) => path.extension().and_then(OsStr::to_str) == Some("gz"),
+        Image::Url(_) | Image::Stdin => true,
+    }
+}
+
 async fn setup_remote_input(url: Url) -> Result<Response> {
     match PathBuf::from(url.path())
         .extension()
@@ -158,85 +548,867 @@ async fn setup_remote_input(url: Url) -> Result<Response> {
     }
 }
 
-fn setup_progress_bar(bmap: &Bmap) -> ProgressBar {
-    let pb = ProgressBar::new(bmap.total_mapped_size());
-    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+/// Read the start of the image, which holds its partition table
+async fn read_image_head(image: &Image) -> Result<Vec<u8>> {
+    let limit = PartitionTable::HEAD_SIZE as u64;
+    let mut head = Vec::new();
+    match image {
+        Image::Path(path) => {
+            ensure!(path.exists(), "Image file doesn't exist");
+            setup_local_input(path)?
+                .take(limit)
+                .read_to_end(&mut head)?;
+        }
+        Image::Url(url) => {
+            let res = setup_remote_input(url.clone()).await?;
+            let stream = res
+                .bytes_stream()
+                .map_err(std::io::Error::other)
+                .into_async_read();
+            GzipDecoder::new(stream)
+                .take(limit)
+                .read_to_end(&mut head)
+                .await?;
+        }
+        // The partition table would have to be read ahead of copying, which stdin only allows
+        // once
+        Image::Stdin => bail!("--partition can't be used when reading the image from stdin"),
+    }
+    Ok(head)
+}
+
+/// Look up the partition of the image selected on the command line, if any
+async fn find_partition(c: &Copy) -> Result<Option<Partition>> {
+    let Some(name) = &c.partition else {
+        return Ok(None);
+    };
+    let head = read_image_head(&c.image).await?;
+    let table = PartitionTable::parse(&head).context("Failed to parse image partition table")?;
+    let partition = table.find(name).ok_or_else(|| {
+        anyhow!(
+            "Partition {} not found in the {} partition table",
+            name,
+            table.table_type()
+        )
+    })?;
+    println!(
+        "Copying partition {}{}: {} at offset {}",
+        partition.number(),
+        partition
+            .label()
+            .map(|l| format!(" ({l})"))
+            .unwrap_or_default(),
+        HumanBytes(partition.size()),
+        partition.offset()
+    );
+    Ok(Some(partition.clone()))
+}
+
+fn restrict_bmap(bmap: Bmap, partition: Option<&Partition>) -> Bmap {
+    match partition {
+        Some(p) => bmap.restrict(p.offset(), p.size()),
+        None => bmap,
+    }
+}
+
+/// Limit the input to the content of the selected partition
+fn restrict_input(mut input: Decoder, partition: Option<&Partition>) -> Result<Decoder> {
+    match partition {
+        Some(p) => {
+            input.seek_forward(p.offset())?;
+            Ok(Decoder::new(Discarder::new(input.take(p.size()))))
+        }
+        None => Ok(input),
+    }
+}
+
+fn setup_progress_bar(bmap: &Bmap, c: &Copy) -> ProgressBar {
+    let len = if c.zero_holes {
+        bmap.image_size()
+    } else {
+        bmap.total_mapped_size()
+    };
+    let pb = ProgressBar::new(len);
+    pb.set_style(ProgressStyle::with_template("{spinner:.green} {prefix}[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}")
         .unwrap()
         .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
         .progress_chars("#>-"));
     pb
 }
 
+fn setup_copy_options(c: &Copy, dest_size: Option<u64>, pbs: &[ProgressBar]) -> CopyOptions {
+    let mut options = CopyOptions::new();
+    options.dest_offset(c.dest_offset);
+    if let Some(size) = dest_size {
+        options.dest_size(size);
+    }
+    options
+        .image_digest(c.image_digest || c.sha256sums.is_some())
+        .continue_on_error(c.continue_on_error)
+        .limit_input_rate(c.limit_rate.input)
+        .input_reads_seeks(reads_seeks(&c.image))
+        .limit_output_rate(c.limit_rate.output);
+    if c.zero_holes {
+        let pbs = pbs.to_vec();
+        let zeroed = AtomicU64::new(0);
+        options.zero_holes(true).on_zero_progress(move |len| {
+            let total = zeroed.fetch_add(len, Ordering::Relaxed) + len;
+            for pb in pbs.iter() {
+                pb.set_message(format!("{} zeroed", HumanBytes(total)));
+            }
+        });
+    }
+    options
+}
+
+fn setup_nobmap_options(
+    c: &Copy,
+    dest_size: Option<u64>,
+    metadata: &[std::fs::Metadata],
+) -> CopyOptions {
+    let mut options = CopyOptions::new();
+    options.dest_offset(c.dest_offset);
+    if let Some(size) = dest_size {
+        options.dest_size(size);
+    }
+    // Skipping zero blocks only leaves zeroes behind on freshly truncated files or when the
+    // skipped ranges get discarded afterwards
+    options.sparse((c.dest_offset == 0 && metadata.iter().all(|m| m.is_file())) || c.discard);
+    options
+        .limit_input_rate(c.limit_rate.input)
+        .limit_output_rate(c.limit_rate.output);
+    options
+}
+
+fn finish_nobmap<T: AsFd>(
+    output: &T,
+    map: &SparseMap,
+    c: &Copy,
+    metadata: std::fs::Metadata,
+) -> Result<()> {
+    grow_file(output, c.dest_offset + map.image_size(), &metadata)?;
+    if c.discard {
+        println!("Discarding zero ranges...");
+        // Zero blocks were skipped, relying on the discarded ranges reading back zeroes
+        let method = DiscardMethod::for_output(&metadata, &blockdev::System::default(), true)?;
+        let holes = map
+            .holes()
+            .map(|h| h.start + c.dest_offset..h.end + c.dest_offset);
+        let discarded = device::discard_ranges(output, method, holes)?;
+        println!("Discarded {}", HumanBytes(discarded));
+    }
+    println!(
+        "Mapped {} of {}",
+        HumanBytes(map.mapped_size()),
+        HumanBytes(map.image_size())
+    );
+    Ok(())
+}
+
 fn setup_spinner() -> ProgressBar {
     let pb = ProgressBar::new_spinner();
-    pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+    pb.set_style(ProgressStyle::with_template("{spinner:.green} {prefix}{msg}").unwrap());
     pb
 }
 
-fn setup_output<T: AsFd>(output: &T, bmap: &Bmap, metadata: std::fs::Metadata) -> Result<()> {
-    if metadata.is_file() {
-        ftruncate(output.as_fd(), bmap.image_size() as i64).context("Failed to truncate file")?;
+/// Open a destination for writing, making sure nothing else writes to it at the same time. Block
+/// devices are opened exclusively, which fails while the kernel uses them e.g. for a mount, unless
+/// `--force` is given. Destinations only need to be readable to update their partition table.
+fn open_output(path: &Path, c: &Copy) -> Result<(File, Lock)> {
+    let system = blockdev::System::default();
+    if let (true, Some(stdout)) = (path == Path::new(STDOUT), &c.stdout) {
+        let output = stdout.try_clone()?;
+        let lock = device::lock_output(path, &output, &system)?;
+        return Ok((output, lock));
+    }
+    let block_device = std::fs::metadata(path)
+        .ok()
+        .filter(|m| m.file_type().is_block_device());
+
+    let mut options = std::fs::OpenOptions::new();
+    options
+        .read(c.relocate_gpt || c.grow_last_partition)
+        .write(true);
+    let mut flags = 0;
+    if block_device.is_none() {
+        options.create(true).truncate(false);
+    } else if !c.force {
+        flags |= nix::libc::O_EXCL;
+    }
+    if block_device.is_some() && c.continue_on_error {
+        // Have failing writes fail right away rather than on writeback, so they can be retried
+        flags |= nix::libc::O_DSYNC;
+    }
+    options.custom_flags(flags);
+    let output = match (options.open(path), &block_device) {
+        (Ok(output), _) => output,
+        (Err(e), Some(metadata)) if e.raw_os_error() == Some(nix::libc::EBUSY) => {
+            let users: Vec<_> = system
+                .users(metadata.rdev())
+                .unwrap_or_default()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            if users.is_empty() {
+                bail!("{} is busy: opened exclusively elsewhere", path.display());
+            }
+            bail!("{} is busy: {}", path.display(), users.join(", "));
+        }
+        (Err(e), _) => return Err(e).with_context(|| format!("Failed to open {}", path.display())),
+    };
+
+    let lock = device::lock_output(path, &output, &system)?;
+    if let (Some(expected), Some(_)) = (c.expect_size, &block_device) {
+        let size = device::fixed_size(&output, &output.metadata()?)?.unwrap_or(0);
+        ensure!(
+            size == expected,
+            "{} is {} bytes instead of the expected {} bytes",
+            path.display(),
+            size,
+            expected
+        );
+    }
+    Ok((output, lock))
+}
+
+/// Take stdout over for writing the image to, sending everything printed to stderr instead
+fn take_stdout() -> Result<File> {
+    ensure!(
+        !io::stdout().is_terminal(),
+        "Not writing the image to a terminal; redirect stdout to a file or pipe"
+    );
+    let stdout = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    nix::unistd::dup2_stdout(io::stderr()).context("Failed to redirect stdout to stderr")?;
+    Ok(stdout)
+}
+
+/// Whether a destination can only be written front to back, like a pipe
+fn is_stream(metadata: &std::fs::Metadata) -> bool {
+    !metadata.is_file() && !metadata.file_type().is_block_device()
+}
+
+fn dest_metadata(path: &Path, c: &Copy) -> Option<std::fs::Metadata> {
+    match &c.stdout {
+        Some(stdout) if path == Path::new(STDOUT) => stdout.metadata().ok(),
+        _ => std::fs::metadata(path).ok(),
+    }
+}
+
+/// Write back the destination, which streams don't support
+fn sync_output(output: &File) -> Result<()> {
+    if !is_stream(&output.metadata()?) {
+        output.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Drop the existing content of a regular file destination when the image gets written at its
+/// start. Only done once the destination is locked and the image is known to fit on it.
+fn truncate_output<T: AsFd>(output: &T, metadata: &std::fs::Metadata, c: &Copy) -> Result<()> {
+    if metadata.is_file() && c.dest_offset == 0 {
+        ftruncate(output.as_fd(), 0).context("Failed to truncate file")?;
+    }
+    Ok(())
+}
+
+/// Make sure a regular file is at least `size` bytes long
+fn grow_file<T: AsFd>(output: &T, size: u64, metadata: &std::fs::Metadata) -> Result<()> {
+    if metadata.is_file() && (fstat(output.as_fd())?.st_size as u64) < size {
+        ftruncate(output.as_fd(), size as i64).context("Failed to truncate file")?;
+    }
+    Ok(())
+}
+
+/// Make sure a regular file destination has enough room on its filesystem for the image
+fn check_free_space<T: AsFd>(
+    output: &T,
+    metadata: &std::fs::Metadata,
+    bmap: &Bmap,
+    c: &Copy,
+) -> Result<()> {
+    if !metadata.is_file() || c.force {
+        return Ok(());
+    }
+    // Holes are only allocated when zeroing them
+    let needed = if c.zero_holes {
+        bmap.image_size()
+    } else {
+        bmap.total_mapped_size()
+    };
+    let available = device::available_space(output, metadata)?;
+    ensure!(
+        needed <= available,
+        "Image needs {} of space but only {} is available for the destination; use --force to copy anyway",
+        HumanBytes(needed),
+        HumanBytes(available)
+    );
+    Ok(())
+}
+
+/// Make sure the image fits on a destination of a fixed size before anything gets written. With
+/// `--force` an image which only fits without its trailing unmapped space gets cut short.
+fn fit_image(bmap: Bmap, dest_size: Option<u64>, dest_offset: u64, force: bool) -> Result<Bmap> {
+    let Some(size) = dest_size else {
+        return Ok(bmap);
+    };
+    let available = size.saturating_sub(dest_offset);
+    if bmap.image_size() <= available {
+        return Ok(bmap);
+    }
+
+    let mut msg = format!(
+        "Image of {} with mapped data up to {} doesn't fit on the destination of {}",
+        HumanBytes(bmap.image_size()),
+        HumanBytes(bmap.mapped_end()),
+        HumanBytes(size)
+    );
+    if dest_offset > 0 {
+        write!(msg, " at offset {}", dest_offset)?;
+    }
+    ensure!(bmap.mapped_end() <= available, msg);
+    ensure!(
+        force,
+        "{msg}; use --force to drop the trailing unmapped space"
+    );
+
+    println!(
+        "Dropping {} of trailing unmapped space from the image",
+        HumanBytes(bmap.image_size() - available)
+    );
+    Ok(bmap.restrict(0, available))
+}
+
+/// Unit to align writes to on the destination and the offset of the image relative to the
+/// start of the disk, if writes are to be aligned
+fn write_alignment(metadata: &std::fs::Metadata, c: &Copy) -> Result<Option<(u64, u64)>> {
+    let Some(alignment) = c.align_writes else {
+        return Ok(None);
+    };
+    let system = blockdev::System::default();
+    let block_device = metadata.file_type().is_block_device();
+    let unit = match alignment {
+        WriteAlignment::Auto if block_device => system.erase_size(metadata.rdev())?,
+        WriteAlignment::Auto => None,
+        WriteAlignment::Bytes(unit) => Some(unit),
+    };
+    let start = match block_device {
+        true => system.partition_start(metadata.rdev())?,
+        false => 0,
+    };
+    Ok(unit
+        .filter(|&unit| unit > 0)
+        .map(|unit| (unit, start + c.dest_offset)))
+}
+
+/// Merge writes separated by small gaps and pad them to line up with the given alignment on the
+/// destination, as asked for
+fn arrange_writes(bmap: Bmap, alignment: Option<(u64, u64)>, c: &Copy) -> Bmap {
+    let bmap = match c.coalesce {
+        0 => bmap,
+        gap => bmap.coalesce(gap),
+    };
+    match alignment {
+        Some((unit, origin)) => {
+            println!("Aligning writes to {}", HumanBytes(unit));
+            bmap.align_writes(unit, origin)
+        }
+        None => bmap,
+    }
+}
+
+/// Keep the dirty page cache of a block device destination bounded while it's written
+fn setup_throttling(output: &File, c: &Copy) -> Result<(Option<DirtyLimit>, Option<BdiTuning>)> {
+    let metadata = output.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok((None, None));
     }
+    let limit = match c.dirty_limit {
+        0 => None,
+        window => Some(DirtyLimit::new(output.try_clone()?, window)),
+    };
+    let tuning = c
+        .tune_bdi
+        .then(|| {
+            throttle::handle_interrupts()?;
+            BdiTuning::apply(&blockdev::System::default(), metadata.rdev())
+        })
+        .transpose()?;
+    Ok((limit, tuning))
+}
+
+fn setup_output<T: AsFd>(
+    output: &T,
+    bmap: &Bmap,
+    c: &Copy,
+    metadata: std::fs::Metadata,
+) -> Result<()> {
+    truncate_output(output, &metadata, c)?;
+    grow_file(output, c.dest_offset + bmap.image_size(), &metadata)
+}
+
+fn discard_unmapped<T: AsFd>(
+    output: &T,
+    bmap: &Bmap,
+    c: &Copy,
+    metadata: std::fs::Metadata,
+) -> Result<()> {
+    println!("Discarding unmapped ranges...");
+    let method = DiscardMethod::for_output(&metadata, &blockdev::System::default(), false)?;
+    let unmapped = bmap
+        .unmapped_ranges()
+        .map(|r| r.start + c.dest_offset..r.end + c.dest_offset);
+    let discarded = device::discard_ranges(output, method, unmapped)?;
+    println!("Discarded {}", HumanBytes(discarded));
     Ok(())
 }
 
-async fn copy(c: Copy) -> Result<()> {
-    if c.nobmap {
-        return match c.image {
-            Image::Path(path) => copy_local_input_nobmap(path, c.dest),
-            Image::Url(url) => copy_remote_input_nobmap(url, c.dest).await,
+/// Update the partition table copied to the destination to match the destination size
+fn fixup_partition_table(output: &File, c: &Copy) -> Result<()> {
+    if !c.relocate_gpt && !c.grow_last_partition {
+        return Ok(());
+    }
+    let metadata = output.metadata()?;
+    let size = device::fixed_size(output, &metadata)?.unwrap_or(metadata.len());
+    let mut disk = output;
+    if c.grow_last_partition {
+        // Growing moves the GPT backup header as well
+        match bmap_parser::grow_last_partition(&mut disk, size)
+            .context("Failed to grow the last partition")?
+        {
+            Some(p) => println!("Grew partition {} to {}", p.number(), HumanBytes(p.size())),
+            None => println!("No partitions found on the destination, not growing any"),
+        }
+    } else if bmap_parser::relocate_gpt_backup(&mut disk, size)
+        .context("Failed to relocate the GPT backup header")?
+    {
+        println!("Relocated GPT backup header to the end of the destination");
+    } else {
+        println!("No GPT found on the destination, not relocating the backup header");
+    }
+    Ok(())
+}
+
+/// Fix up the partition table after copying the image, unless parts of it couldn't be copied
+/// and the table itself may be among them
+fn fixup_copied_partition_table(output: &File, report: &CopyReport, c: &Copy) -> Result<()> {
+    if report.has_errors() && (c.relocate_gpt || c.grow_last_partition) {
+        println!("Not fixing up the partition table, as parts of the image failed to be copied");
+        return Ok(());
+    }
+    fixup_partition_table(output, c)
+}
+
+/// Refuse to write to block devices which are mounted or otherwise in use
+fn check_not_in_use(path: &Path, c: &Copy) -> Result<()> {
+    if c.force {
+        return Ok(());
+    }
+    let Ok(metadata) = std::fs::metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_block_device() {
+        return Ok(());
+    }
+    let users = blockdev::System::default()
+        .users(metadata.rdev())
+        .with_context(|| format!("Failed to check whether {} is in use", path.display()))?;
+    if !users.is_empty() {
+        let users: Vec<_> = users.iter().map(ToString::to_string).collect();
+        bail!(
+            "{} is in use: {}; use --force to write to it anyway",
+            path.display(),
+            users.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Show what's on the block devices about to be overwritten and ask before going ahead. Without
+/// a terminal to ask on `--yes` is required.
+fn confirm_overwrite(c: &Copy) -> Result<()> {
+    if c.yes {
+        return Ok(());
+    }
+    let system = blockdev::System::default();
+    let mut devices = Vec::new();
+    for path in c.dest.iter() {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            continue;
         };
+        if !metadata.file_type().is_block_device() {
+            continue;
+        }
+        let contents = system
+            .contents(metadata.rdev())
+            .with_context(|| format!("Failed to read what's on {}", path.display()))?;
+        println!(
+            "{}: {} ({})",
+            path.display(),
+            contents.description(),
+            HumanBytes(contents.size)
+        );
+        if let Some(label) = &contents.label {
+            println!("  filesystem \"{label}\"");
+        }
+        for p in contents.partitions.iter() {
+            match &p.label {
+                Some(label) => println!(
+                    "  {}: {}, filesystem \"{label}\"",
+                    p.name,
+                    HumanBytes(p.size)
+                ),
+                None => println!("  {}: {}", p.name, HumanBytes(p.size)),
+            }
+        }
+        devices.push(path.display().to_string());
     }
-    match c.image {
-        Image::Path(path) => copy_local_input(path, c.dest),
-        Image::Url(url) => copy_remote_input(url, c.dest).await,
+    if devices.is_empty() {
+        return Ok(());
     }
+
+    let devices = devices.join(", ");
+    ensure!(
+        !matches!(c.image, Image::Stdin) && io::stdin().is_terminal(),
+        "Not overwriting {devices} without confirmation; use --yes when not running interactively"
+    );
+    print!("All data on {devices} will be lost. Continue? [y/N] ");
+    io::Write::flush(&mut io::stdout())?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    ensure!(
+        matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        "Aborted"
+    );
+    Ok(())
 }
 
-fn copy_local_input(source: PathBuf, destination: PathBuf) -> Result<()> {
-    ensure!(source.exists(), "Image file doesn't exist");
-    let bmap = find_bmap(&source).ok_or_else(|| anyhow!("Couldn't find bmap file"))?;
-    println!("Found bmap file: {}", bmap.display());
+/// Whether anything is to be done to the destination disks once they've been written
+fn has_housekeeping(c: &Copy) -> bool {
+    c.reread_partitions || c.flush_cache || c.eject || c.power_off
+}
 
-    let mut b = File::open(&bmap).context("Failed to open bmap file")?;
-    let mut xml = String::new();
-    b.read_to_string(&mut xml)?;
+/// Whole disks of the destinations, for housekeeping which acts on the disk as a whole
+fn destination_disks(c: &Copy, system: &blockdev::System) -> Result<Vec<PathBuf>> {
+    c.dest
+        .iter()
+        .map(|path| {
+            let metadata = std::fs::metadata(path)
+                .with_context(|| format!("Failed to stat {}", path.display()))?;
+            ensure!(
+                metadata.file_type().is_block_device(),
+                "{} is not a block device; --reread-partitions, --flush-cache, --eject and \
+                 --power-off only work on block devices",
+                path.display()
+            );
+            Ok(match system.whole_disk(metadata.rdev())? {
+                Some(disk) => system.dev_root.join(disk),
+                None => path.clone(),
+            })
+        })
+        .collect()
+}
 
-    let bmap = Bmap::from_xml(&xml)?;
-    let output = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(destination)?;
+/// Flush, re-read the partition table and eject or power off the destinations as asked for,
+/// reporting every step
+fn finish_devices(c: &Copy) -> Result<()> {
+    if !has_housekeeping(c) {
+        return Ok(());
+    }
+    let system = blockdev::System::default();
+    for disk in destination_disks(c, &system)? {
+        let name = disk.display();
+        let file = File::open(&disk).with_context(|| format!("Failed to open {name}"))?;
+        if c.flush_cache {
+            device::flush_cache(&file).with_context(|| name.to_string())?;
+            println!("{name}: Flushed write cache");
+        }
+        if c.reread_partitions {
+            device::reread_partitions(&file).with_context(|| name.to_string())?;
+            println!("{name}: Re-read partition table");
+        }
+        drop(file);
 
-    setup_output(&output, &bmap, output.metadata()?)?;
+        // The kernel name is what sysfs knows the disk by
+        let kernel_name = std::fs::canonicalize(&disk)?
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .with_context(|| format!("Invalid device path {name}"))?;
+        if c.power_off {
+            system.power_off(&kernel_name)?;
+            println!("{name}: Powered off, safe to unplug");
+        } else if c.eject {
+            system.eject(&kernel_name)?;
+            println!("{name}: Ejected");
+        }
+    }
+    Ok(())
+}
 
-    let mut input = setup_local_input(&source)?;
-    let pb = setup_progress_bar(&bmap);
-    bmap_parser::copy(&mut input, &mut pb.wrap_write(&output), &bmap)?;
-    pb.finish_and_clear();
+async fn copy(mut c: Copy) -> Result<()> {
+    let to_stdout = c.dest.iter().filter(|d| *d == Path::new(STDOUT)).count();
+    ensure!(
+        to_stdout <= 1,
+        "stdout can only be given once as destination"
+    );
+    if to_stdout > 0 {
+        // Before anything gets printed to it
+        c.stdout = Some(take_stdout()?);
+    }
+    if !c.dest_match.is_empty() {
+        let disk = blockdev::System::default().find_disk(&c.dest_match)?;
+        println!(
+            "Selected destination {}: {} ({})",
+            disk.path.display(),
+            disk.description(),
+            HumanBytes(disk.size)
+        );
+        c.dest.push(disk.path);
+    }
+    ensure!(
+        !c.continue_on_error || c.dest.len() == 1,
+        "--continue-on-error only supports a single destination"
+    );
+    if c.dest
+        .iter()
+        .filter_map(|d| dest_metadata(d, &c))
+        .any(|m| is_stream(&m))
+    {
+        ensure!(
+            !c.discard
+                && !c.relocate_gpt
+                && !c.grow_last_partition
+                && !c.continue_on_error
+                && c.dest_offset == 0,
+            "--discard, --relocate-gpt, --grow-last-partition, --continue-on-error and \
+             --dest-offset need destinations which can be seeked in, not pipes"
+        );
+        // Unmapped ranges can't be seeked over in a pipe, so they're written out as zeroes
+        c.zero_holes = !c.nobmap;
+    }
+    for dest in c.dest.iter() {
+        check_not_in_use(dest, &c)?;
+    }
+    if has_housekeeping(&c) {
+        // Fail before copying rather than after
+        destination_disks(&c, &blockdev::System::default())?;
+    }
+    let expected_digest = match c.sha256sums {
+        Some(ref sums) => Some(sums::expected_digest(sums, &c.image).await?),
+        None => None,
+    };
+    confirm_overwrite(&c)?;
+    let partition = find_partition(&c).await?;
+    let partition = partition.as_ref();
+    let bmap = match c.nobmap {
+        true => None,
+        false => Some(restrict_bmap(
+            bmap_file::load(c.bmap.as_deref(), &c.image).await?,
+            partition,
+        )),
+    };
+    let copied = if c.dest.len() > 1 {
+        fanout::copy(&c, bmap, partition).await?.map(Copied::from)
+    } else if let Some(bmap) = bmap {
+        Some(match c.image {
+            Image::Url(ref url) => copy_remote_input(url.clone(), bmap, &c).await?.into(),
+            _ => copy_local_input(&c.image, bmap, &c)?,
+        })
+    } else {
+        match c.image {
+            Image::Url(ref url) => copy_remote_input_nobmap(url.clone(), &c, partition).await?,
+            _ => copy_local_input_nobmap(&c.image, &c, partition)?,
+        }
+        None
+    };
+    if let Some(copied) = &copied {
+        print_summary(copied);
+    }
+    let report = copied.map(|copied| copied.report);
+    if let Some(report) = report.as_ref().filter(|_| c.continue_on_error) {
+        check_errors(report, &c)?;
+    }
+    if let Some(digest) = report.as_ref().and_then(CopyReport::image_digest) {
+        check_image_digest(&sums::hex(&digest), expected_digest.as_deref())?;
+    }
+    finish_devices(&c)
+}
 
-    println!("Done: Syncing...");
-    output.sync_all()?;
+/// Outcome of copying an image
+struct Copied {
+    report: CopyReport,
+    /// Writes made inside the kernel, when the copy only verified the image
+    kernel: Option<KernelCopy>,
+}
+
+impl From<CopyReport> for Copied {
+    fn from(report: CopyReport) -> Self {
+        Copied {
+            report,
+            kernel: None,
+        }
+    }
+}
+
+fn print_summary(copied: &Copied) {
+    let report = &copied.report;
+    let (written, skipped, write_time) = match copied.kernel {
+        Some(k) => (k.cloned + k.copied, k.skipped, k.elapsed),
+        None => (
+            report.bytes_written(),
+            report.bytes_skipped(),
+            report.write_time(),
+        ),
+    };
+    let elapsed = report.elapsed() + copied.kernel.map_or(Duration::ZERO, |k| k.elapsed);
+    let throughput = match elapsed.as_secs_f64() {
+        0.0 => 0,
+        secs => (written as f64 / secs) as u64,
+    };
+    println!(
+        "Copied {} ranges: read {}, discarded {}, wrote {}, skipped {}",
+        report.ranges(),
+        HumanBytes(report.bytes_read()),
+        HumanBytes(report.bytes_discarded()),
+        HumanBytes(written),
+        HumanBytes(skipped)
+    );
+    if let Some(merged) = report.coalesced() {
+        println!(
+            "Coalesced them into {} ranges, verified including the bridged data",
+            merged.block_map().len()
+        );
+    }
+    println!(
+        "Took {:.2}s ({:.2}s decoding, {:.2}s hashing, {:.2}s writing, {:.2}s syncing), {}/s on average",
+        elapsed.as_secs_f64(),
+        report.decode_time().as_secs_f64(),
+        report.hash_time().as_secs_f64(),
+        write_time.as_secs_f64(),
+        report.sync_time().as_secs_f64(),
+        HumanBytes(throughput)
+    );
+}
 
+/// Report the errors skipped over while copying, failing if there were any
+fn check_errors(report: &CopyReport, c: &Copy) -> Result<()> {
+    let errors = bad_blocks::ErrorReport::new(&c.dest[0], report, c.dest_offset);
+    if let Some(path) = &c.error_report {
+        errors.write(path)?;
+    }
+    for region in errors.bad_blocks.iter() {
+        println!(
+            "Bad blocks: {} bytes at offset {}",
+            region.length, region.offset
+        );
+    }
+    for region in errors.unverified.iter() {
+        println!(
+            "Failed verification: {} bytes at offset {}",
+            region.length, region.offset
+        );
+    }
+    if report.has_errors() {
+        let total = |regions: &[bad_blocks::Region]| regions.iter().map(|r| r.length).sum();
+        bail!(
+            "{} couldn't be written and {} failed verification",
+            HumanBytes(total(&errors.bad_blocks)),
+            HumanBytes(total(&errors.unverified))
+        );
+    }
     Ok(())
 }
 
-async fn copy_remote_input(source: Url, destination: PathBuf) -> Result<()> {
-    let bmap_url = find_remote_bmap(source.clone())?;
+fn check_image_digest(digest: &str, expected: Option<&str>) -> Result<()> {
+    println!("Image SHA-256: {digest}");
+    match expected {
+        Some(expected) if expected != digest => {
+            bail!("Image digest doesn't match the published digest {expected}")
+        }
+        Some(_) => println!("Image digest matches the published digest"),
+        None => (),
+    }
+    Ok(())
+}
+
+fn copy_local_input(image: &Image, bmap: Bmap, c: &Copy) -> Result<Copied> {
+    let mut input = setup_sync_input(image)?;
+    let (output, _lock) = open_output(&c.dest[0], c)?;
+    let (limit, _tuning) = setup_throttling(&output, c)?;
+
+    let metadata = output.metadata()?;
+    let dest_size = device::fixed_size(&output, &metadata)?;
+    check_free_space(&output, &metadata, &bmap, c)?;
+    let bmap = fit_image(bmap, dest_size, c.dest_offset, c.force)?;
+    let bmap = arrange_writes(bmap, write_alignment(&metadata, c)?, c);
+    // Uncompressed images can be copied between files by the kernel, which leaves holes alone
+    let kernel_copy = match image {
+        Image::Path(source)
+            if metadata.is_file()
+                && !c.zero_holes
+                && !c.continue_on_error
+                && c.limit_rate.input == 0
+                && c.limit_rate.output == 0
+                && source.extension().and_then(OsStr::to_str) != Some("gz") =>
+        {
+            Some(source)
+        }
+        _ => None,
+    };
+    setup_output(&output, &bmap, c, metadata)?;
+
+    let pb = setup_progress_bar(&bmap, c);
+    let options = setup_copy_options(c, dest_size, std::slice::from_ref(&pb));
+    let (mut report, kernel) = if let Some(source) = kernel_copy {
+        // Verify the whole image before the kernel copies it, reading it only to hash it
+        let report = bmap_parser::copy_with_options(
+            &mut input,
+            &mut pb.wrap_write(io::empty()),
+            &bmap,
+            &options,
+        )?;
+        pb.finish_and_clear();
+        let copied = kernel_copy::copy_ranges(&File::open(source)?, &output, &bmap, c.dest_offset)?;
+        println!(
+            "Cloned {}, copied {} inside the kernel",
+            HumanBytes(copied.cloned),
+            HumanBytes(copied.copied)
+        );
+        (report, Some(copied))
+    } else {
+        let report = bmap_parser::copy_with_options(
+            &mut input,
+            &mut pb.wrap_write(Throttled::new(&output, limit)),
+            &bmap,
+            &options,
+        )?;
+        pb.finish_and_clear();
+        (report, None)
+    };
+
+    if c.discard {
+        discard_unmapped(&output, &bmap, c, output.metadata()?)?;
+    }
+    fixup_copied_partition_table(&output, &report, c)?;
+
+    println!("Done: Syncing...");
+    let syncing = Instant::now();
+    sync_output(&output)?;
+    report.add_sync_time(syncing.elapsed());
 
-    let xml = reqwest::get(bmap_url.clone()).await?.text().await?;
-    println!("Found bmap file: {}", bmap_url);
+    Ok(Copied { report, kernel })
+}
 
-    let bmap = Bmap::from_xml(&xml)?;
-    let mut output = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(destination)
-        .await?;
+async fn copy_remote_input(source: Url, bmap: Bmap, c: &Copy) -> Result<CopyReport> {
+    let (output, _lock) = open_output(&c.dest[0], c)?;
+    let (limit, _tuning) = setup_throttling(&output, c)?;
+    let mut output = tokio::fs::File::from_std(output);
 
-    setup_output(&output, &bmap, output.metadata().await?)?;
+    let metadata = output.metadata().await?;
+    let dest_size = device::fixed_size(&output, &metadata)?;
+    check_free_space(&output, &metadata, &bmap, c)?;
+    let bmap = fit_image(bmap, dest_size, c.dest_offset, c.force)?;
+    let bmap = arrange_writes(bmap, write_alignment(&metadata, c)?, c);
+    setup_output(&output, &bmap, c, metadata)?;
 
     let res = setup_remote_input(source).await?;
     let stream = res
@@ -244,49 +1416,84 @@ async fn copy_remote_input(source: Url, destination: PathBuf) -> Result<()> {
         .map_err(std::io::Error::other)
         .into_async_read();
     let reader = GzipDecoder::new(stream);
-    let mut input = AsyncDiscarder::new(reader);
-    let pb = setup_progress_bar(&bmap);
-    bmap_parser::copy_async(
-        &mut input,
-        &mut pb.wrap_async_write(&mut output).compat(),
-        &bmap,
-    )
-    .await?;
+    let pb = setup_progress_bar(&bmap, c);
+    let options = setup_copy_options(c, dest_size, std::slice::from_ref(&pb));
+    let mut report = if c.continue_on_error {
+        // Asynchronous files only report a write failing on the next call, so blocks failing to
+        // be written can't be told apart. Copy synchronously instead
+        let reader = FuturesAsyncReadCompatExt::compat(reader);
+        let mut input = Decoder::new(Discarder::new(SyncIoBridge::new(reader)));
+        let output = output.try_clone().await?.into_std().await;
+        tokio::task::block_in_place(|| {
+            bmap_parser::copy_with_options(
+                &mut input,
+                &mut pb.wrap_write(Throttled::new(&output, limit)),
+                &bmap,
+                &options,
+            )
+        })?
+    } else {
+        let mut input = AsyncDiscarder::new(reader);
+        bmap_parser::copy_async_with_options(
+            &mut input,
+            &mut Throttled::new(pb.wrap_async_write(&mut output).compat(), limit),
+            &bmap,
+            &options,
+        )
+        .await?
+    };
     pb.finish_and_clear();
 
+    if c.discard {
+        discard_unmapped(&output, &bmap, c, output.metadata().await?)?;
+    }
+    fixup_copied_partition_table(&output.try_clone().await?.into_std().await, &report, c)?;
+
     println!("Done: Syncing...");
-    output.sync_all().await?;
-    Ok(())
+    let syncing = Instant::now();
+    if !is_stream(&output.metadata().await?) {
+        output.sync_all().await?;
+    }
+    report.add_sync_time(syncing.elapsed());
+    Ok(report)
 }
 
-fn copy_local_input_nobmap(source: PathBuf, destination: PathBuf) -> Result<()> {
-    ensure!(source.exists(), "Image file doesn't exist");
+fn copy_local_input_nobmap(image: &Image, c: &Copy, partition: Option<&Partition>) -> Result<()> {
+    let input = setup_sync_input(image)?;
 
-    let output = std::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(destination)?;
+    let (output, _lock) = open_output(&c.dest[0], c)?;
+    let (limit, _tuning) = setup_throttling(&output, c)?;
 
-    let mut input = setup_local_input(&source)?;
+    let mut input = restrict_input(input, partition)?;
 
+    let metadata = output.metadata()?;
+    truncate_output(&output, &metadata, c)?;
+    let dest_size = device::fixed_size(&output, &metadata)?;
+    let options = setup_nobmap_options(c, dest_size, std::slice::from_ref(&metadata));
     let pb = setup_spinner();
-    bmap_parser::copy_nobmap(&mut input, &mut pb.wrap_write(&output))?;
+    let map = bmap_parser::copy_nobmap_with_options(
+        &mut input,
+        &mut pb.wrap_write(Throttled::new(&output, limit)),
+        &options,
+    )?;
     pb.finish_and_clear();
+    finish_nobmap(&output, &map, c, metadata)?;
+    fixup_partition_table(&output, c)?;
 
     println!("Done: Syncing...");
-    output.sync_all().expect("Sync failure");
+    sync_output(&output).expect("Sync failure");
 
     Ok(())
 }
 
-async fn copy_remote_input_nobmap(source: Url, destination: PathBuf) -> Result<()> {
-    let mut output = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(destination)
-        .await?;
+async fn copy_remote_input_nobmap(
+    source: Url,
+    c: &Copy,
+    partition: Option<&Partition>,
+) -> Result<()> {
+    let (output, _lock) = open_output(&c.dest[0], c)?;
+    let (limit, _tuning) = setup_throttling(&output, c)?;
+    let mut output = tokio::fs::File::from_std(output);
 
     let res = setup_remote_input(source).await?;
     let stream = res
@@ -295,13 +1502,29 @@ async fn copy_remote_input_nobmap(source: Url, destination: PathBuf) -> Result<(
         .into_async_read();
     let reader = GzipDecoder::new(stream);
     let mut input = AsyncDiscarder::new(reader);
+    if let Some(p) = partition {
+        input.async_seek_forward(p.offset()).await?;
+    }
+    let mut input = input.take(partition.map_or(u64::MAX, Partition::size));
+    let metadata = output.metadata().await?;
+    truncate_output(&output, &metadata, c)?;
+    let dest_size = device::fixed_size(&output, &metadata)?;
+    let options = setup_nobmap_options(c, dest_size, std::slice::from_ref(&metadata));
     let pb = setup_spinner();
-    bmap_parser::copy_async_nobmap(&mut input, &mut pb.wrap_async_write(&mut output).compat())
-        .await?;
+    let map = bmap_parser::copy_async_nobmap_with_options(
+        &mut input,
+        &mut Throttled::new(pb.wrap_async_write(&mut output).compat(), limit),
+        &options,
+    )
+    .await?;
     pb.finish_and_clear();
+    finish_nobmap(&output, &map, c, metadata)?;
+    fixup_partition_table(&output.try_clone().await?.into_std().await, c)?;
 
     println!("Done: Syncing...");
-    output.sync_all().await?;
+    if !is_stream(&output.metadata().await?) {
+        output.sync_all().await?;
+    }
     Ok(())
 }
 
@@ -310,6 +1533,67 @@ async fn main() -> Result<()> {
     let opts = Opts::parser();
 
     match opts.command {
-        Subcommand::Copy(c) => copy(c).await,
+        Subcommand::Copy(c) => copy(*c).await,
+        Subcommand::ListDevices(l) => list::list_devices(&l),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bmap_parser::{HashType, HashValue};
+
+    const BLOCK_SIZE: u64 = 4096;
+
+    /// 64 block image with data mapped up to block 45
+    fn bmap() -> Bmap {
+        let mut builder = Bmap::builder();
+        builder
+            .image_size(64 * BLOCK_SIZE)
+            .block_size(BLOCK_SIZE)
+            .blocks(64)
+            .mapped_blocks(11)
+            .checksum_type(HashType::Sha256)
+            .add_block_range(0, 4, HashValue::Sha256([0; 32]))
+            .add_block_range(40, 45, HashValue::Sha256([1; 32]));
+        builder.build().unwrap()
+    }
+
+    #[test]
+    fn fit_image() {
+        let fitted = super::fit_image(bmap(), None, 0, false).unwrap();
+        assert_eq!(64 * BLOCK_SIZE, fitted.image_size());
+        let fitted = super::fit_image(bmap(), Some(64 * BLOCK_SIZE), 0, false).unwrap();
+        assert_eq!(64 * BLOCK_SIZE, fitted.image_size());
+    }
+
+    #[test]
+    fn fit_image_refused() {
+        // Only trailing unmapped space can be dropped, and only with --force
+        assert!(super::fit_image(bmap(), Some(50 * BLOCK_SIZE), 0, false).is_err());
+        assert!(super::fit_image(bmap(), Some(64 * BLOCK_SIZE), BLOCK_SIZE, false).is_err());
+        assert!(super::fit_image(bmap(), Some(45 * BLOCK_SIZE), 0, true).is_err());
+        assert!(super::fit_image(bmap(), Some(46 * BLOCK_SIZE), BLOCK_SIZE, true).is_err());
+    }
+
+    #[test]
+    fn reads_seeks() {
+        assert!(!super::reads_seeks(&Image::Path("image.img".into())));
+        assert!(super::reads_seeks(&Image::Path("image.img.gz".into())));
+        assert!(super::reads_seeks(&Image::Stdin));
+        let url = Url::parse("https://example.com/image.img.gz").unwrap();
+        assert!(super::reads_seeks(&Image::Url(url)));
+    }
+
+    #[test]
+    fn fit_image_forced() {
+        let fitted = super::fit_image(bmap(), Some(50 * BLOCK_SIZE), 0, true).unwrap();
+        assert_eq!(50 * BLOCK_SIZE, fitted.image_size());
+        assert_eq!(bmap().total_mapped_size(), fitted.total_mapped_size());
+        assert_eq!(46 * BLOCK_SIZE, fitted.mapped_end());
+
+        let fitted = super::fit_image(bmap(), Some(64 * BLOCK_SIZE), BLOCK_SIZE, true).unwrap();
+        assert_eq!(63 * BLOCK_SIZE, fitted.image_size());
+        assert_eq!(bmap().total_mapped_size(), fitted.total_mapped_size());
     }
 }
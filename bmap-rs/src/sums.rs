@@ -0,0 +1,106 @@
+use crate::Image;
+use anyhow::{Context, Result, bail};
+use bmap_parser::HashValue;
+use reqwest::Url;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+/// Lowercase hex representation of a digest
+pub fn hex(digest: &HashValue) -> String {
+    digest
+        .as_slice()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Find the digest of a file in the output of sha256sum, which has lines like `<hex>  <name>`,
+/// with a `*` instead of the second space for files hashed in binary mode
+pub fn lookup<'a>(sums: &'a str, name: &str) -> Option<&'a str> {
+    sums.lines().find_map(|line| {
+        let (digest, file) = line.trim_end().split_once(' ')?;
+        let file = file.strip_prefix([' ', '*']).unwrap_or(file);
+        (Path::new(file).file_name() == Some(OsStr::new(name))).then_some(digest)
+    })
+}
+
+/// Name the uncompressed image goes by in a SHA256SUMS file
+fn image_name(image: &Image) -> Option<String> {
+    let path = match image {
+        Image::Path(path) => path.clone(),
+        Image::Url(url) => url.path().into(),
+        Image::Stdin => return None,
+    };
+    let name = match path.extension().and_then(OsStr::to_str) {
+        Some("gz") => path.file_stem()?,
+        _ => path.file_name()?,
+    };
+    name.to_str().map(str::to_string)
+}
+
+/// Fetch the published digest of the uncompressed image from a SHA256SUMS file
+pub async fn expected_digest(sums: &str, image: &Image) -> Result<String> {
+    let text = match Url::parse(sums) {
+        Ok(url) => reqwest::get(url)
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+            .with_context(|| format!("Failed to fetch {sums}"))?,
+        Err(_) => fs::read_to_string(sums).with_context(|| format!("Failed to read {sums}"))?,
+    };
+    let Some(name) = image_name(image) else {
+        bail!("Can't tell the name of the image");
+    };
+    match lookup(&text, &name) {
+        Some(digest) if digest.len() == 64 => Ok(digest.to_ascii_lowercase()),
+        Some(_) => bail!("{sums} has no SHA-256 digest for {name}"),
+        None => bail!("{name} isn't listed in {sums}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    const SUMS: &str = "\
+0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef  image.img.gz
+fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210 *images/image.img
+";
+
+    #[test]
+    fn lookup() {
+        assert_eq!(
+            Some("0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef"),
+            super::lookup(SUMS, "image.img.gz")
+        );
+        assert_eq!(
+            Some("fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210"),
+            super::lookup(SUMS, "image.img")
+        );
+        assert_eq!(None, super::lookup(SUMS, "other.img"));
+    }
+
+    #[test]
+    fn image_name() {
+        let path = Image::Path(PathBuf::from("/tmp/image.img.gz"));
+        assert_eq!(Some("image.img".to_string()), super::image_name(&path));
+        let url = Image::Url(Url::parse("https://example.com/a/image.img.gz?x=1").unwrap());
+        assert_eq!(Some("image.img".to_string()), super::image_name(&url));
+        let path = Image::Path(PathBuf::from("image.img"));
+        assert_eq!(Some("image.img".to_string()), super::image_name(&path));
+    }
+
+    #[test]
+    fn hex() {
+        let mut digest = [0; 32];
+        digest[0] = 0xab;
+        digest[31] = 0x01;
+        let hex = super::hex(&HashValue::Sha256(digest));
+        assert!(hex.starts_with("ab00"));
+        assert!(hex.ends_with("0001"));
+        assert_eq!(64, hex.len());
+    }
+}
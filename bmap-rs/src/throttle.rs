@@ -0,0 +1,298 @@
+use crate::blockdev::{System, disk_dir};
+use anyhow::{Context, Result};
+use futures::io::{AsyncSeek, AsyncWrite};
+use nix::errno::Errno;
+use nix::libc::{self, SYNC_FILE_RANGE_WAIT_BEFORE, SYNC_FILE_RANGE_WRITE, c_int};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, raise, sigaction};
+use nix::unistd::pipe;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, IntoRawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, Once, PoisonError};
+use std::task::{Context as TaskContext, Poll};
+use std::thread;
+
+/// Bounds the amount of dirty page cache a block device accumulates while being written, rather
+/// than leaving it all to the final sync. Every window of data written gets its writeback
+/// started, after waiting for the writeback of the previous window to finish.
+pub struct DirtyLimit {
+    file: File,
+    window: u64,
+    dirty: u64,
+}
+
+impl DirtyLimit {
+    pub fn new(file: File, window: u64) -> Self {
+        Self {
+            file,
+            window,
+            dirty: 0,
+        }
+    }
+
+    /// Account for data written, returning whether a window is complete and due for writeback
+    fn wrote(&mut self, len: usize) -> bool {
+        self.dirty += len as u64;
+        if self.dirty < self.window {
+            return false;
+        }
+        self.dirty = 0;
+        true
+    }
+
+    fn write_back(&self) -> io::Result<()> {
+        // SAFETY: sync_file_range only acts on the file descriptor
+        let r = unsafe {
+            libc::sync_file_range(
+                self.file.as_raw_fd(),
+                0,
+                0,
+                SYNC_FILE_RANGE_WAIT_BEFORE | SYNC_FILE_RANGE_WRITE,
+            )
+        };
+        Errno::result(r)?;
+        Ok(())
+    }
+}
+
+/// Output wrapper applying an optional dirty limit to everything written through it
+pub struct Throttled<W> {
+    inner: W,
+    limit: Option<DirtyLimit>,
+}
+
+impl<W> Throttled<W> {
+    pub fn new(inner: W, limit: Option<DirtyLimit>) -> Self {
+        Self { inner, limit }
+    }
+
+    /// The dirty limit if the data written completes a window due for writeback
+    fn due(&mut self, len: usize) -> Option<&DirtyLimit> {
+        let limit = self.limit.as_mut()?;
+        limit.wrote(len).then_some(limit)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(limit) = self.due(written) {
+            limit.write_back()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for Throttled<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttled<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = match Pin::new(&mut self.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => written,
+            other => return other,
+        };
+        match self.due(written) {
+            // Waiting for writeback blocks, so keep it off the runtime's worker threads
+            Some(limit) => {
+                Poll::Ready(tokio::task::block_in_place(|| limit.write_back()).map(|_| written))
+            }
+            None => Poll::Ready(Ok(written)),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<W: AsyncSeek + Unpin> AsyncSeek for Throttled<W> {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        Pin::new(&mut self.inner).poll_seek(cx, pos)
+    }
+}
+
+/// Original values of all sysfs attributes currently tuned, restored when dropping their tuning
+/// or when interrupted
+static TUNED: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+/// Write end of the pipe the signal handler wakes the restoring thread through
+static INTERRUPT_PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_interrupt(signal: c_int) {
+    let byte = signal as u8;
+    // SAFETY: write is async-signal-safe and only reads the single byte
+    unsafe {
+        libc::write(
+            INTERRUPT_PIPE.load(Ordering::Relaxed),
+            (&raw const byte).cast(),
+            1,
+        )
+    };
+}
+
+fn restore(path: &Path, value: &str) {
+    if let Err(e) = fs::write(path, value) {
+        eprintln!("Failed to restore {} to {}: {}", path.display(), value, e);
+    }
+}
+
+/// Restore the tuning when interrupted by SIGINT or SIGTERM, before dying of the signal as usual.
+/// To be called before applying any tuning; later calls do nothing.
+pub fn handle_interrupts() -> Result<()> {
+    static HANDLER: Once = Once::new();
+    let mut handled = Ok(());
+    HANDLER.call_once(|| handled = install_handler());
+    handled
+}
+
+/// Signal handlers can hardly do anything, so the handler only wakes a thread doing the work
+fn install_handler() -> Result<()> {
+    let (read, write) = pipe().context("Failed to create interrupt pipe")?;
+    INTERRUPT_PIPE.store(write.into_raw_fd(), Ordering::Relaxed);
+    thread::spawn(move || {
+        let mut signal = [0];
+        if File::from(read).read_exact(&mut signal).is_err() {
+            return;
+        }
+        let mut tuned = TUNED.lock().unwrap_or_else(PoisonError::into_inner);
+        for (path, value) in tuned.drain(..).rev() {
+            restore(&path, &value);
+        }
+        if let Ok(signal) = Signal::try_from(signal[0] as c_int) {
+            // SAFETY: Going back to the default action doesn't involve a handler
+            let _ = unsafe { nix::sys::signal::signal(signal, SigHandler::SigDfl) };
+            let _ = raise(signal);
+        }
+    });
+
+    let action = SigAction::new(
+        SigHandler::Handler(on_interrupt),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in [Signal::SIGINT, Signal::SIGTERM] {
+        // SAFETY: The handler only writes to a pipe, which is async-signal-safe
+        unsafe { sigaction(signal, &action) }.context("Failed to install signal handler")?;
+    }
+    Ok(())
+}
+
+/// Temporary writeback tuning of a block device through sysfs, undone when dropped or when
+/// interrupted. The share of the dirty page cache the device may use is limited to 1% and the
+/// I/O scheduler is switched off where possible.
+pub struct BdiTuning {
+    paths: Vec<PathBuf>,
+}
+
+impl BdiTuning {
+    /// Tune the disk of the given device, see [`handle_interrupts`] to also undo it when
+    /// interrupted
+    pub fn apply(system: &System, rdev: u64) -> Result<Self> {
+        let sysfs = system.sysfs_device(rdev)?;
+        // Writeback and scheduling are set up for the disk as a whole
+        let disk = disk_dir(&sysfs);
+
+        let mut tuning = Self { paths: Vec::new() };
+        tuning.set(disk.join("bdi/max_ratio"), "1".to_string())?;
+        let scheduler = disk.join("queue/scheduler");
+        if let Ok(schedulers) = fs::read_to_string(&scheduler) {
+            let available: Vec<_> = schedulers.split_whitespace().collect();
+            if available.contains(&"none") {
+                tuning.set(scheduler, "none".to_string())?;
+            }
+        }
+        Ok(tuning)
+    }
+
+    /// Set a sysfs attribute, remembering its current value to restore later
+    fn set(&mut self, path: PathBuf, value: String) -> Result<()> {
+        let current = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        // The scheduler attribute lists all schedulers with the active one in brackets
+        let current = current
+            .split_whitespace()
+            .find_map(|s| s.strip_prefix('[').and_then(|s| s.strip_suffix(']')))
+            .unwrap_or(current.trim())
+            .to_string();
+        let mut tuned = TUNED.lock().unwrap_or_else(PoisonError::into_inner);
+        fs::write(&path, &value).with_context(|| format!("Failed to write {}", path.display()))?;
+        tuned.push((path.clone(), current));
+        self.paths.push(path);
+        Ok(())
+    }
+}
+
+impl Drop for BdiTuning {
+    fn drop(&mut self) {
+        let mut tuned = TUNED.lock().unwrap_or_else(PoisonError::into_inner);
+        for path in self.paths.drain(..).rev() {
+            if let Some(i) = tuned.iter().rposition(|(p, _)| *p == path) {
+                let (path, value) = tuned.remove(i);
+                restore(&path, &value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nix::sys::stat::makedev;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn bdi_tuning() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        let disk = root.join("sys/devices/virtual/block/sda");
+        fs::create_dir_all(disk.join("bdi")).unwrap();
+        fs::create_dir_all(disk.join("queue")).unwrap();
+        fs::create_dir_all(disk.join("sda1")).unwrap();
+        fs::create_dir_all(root.join("sys/dev/block")).unwrap();
+        fs::write(disk.join("sda1/partition"), "1\n").unwrap();
+        fs::write(disk.join("bdi/max_ratio"), "100\n").unwrap();
+        fs::write(disk.join("queue/scheduler"), "[mq-deadline] kyber none\n").unwrap();
+        symlink(
+            "../../devices/virtual/block/sda/sda1",
+            root.join("sys/dev/block/8:1"),
+        )
+        .unwrap();
+        let system = System {
+            proc_root: root.join("proc"),
+            sys_root: root.join("sys"),
+            dev_root: root.join("dev"),
+        };
+
+        let read = |p: &str| fs::read_to_string(disk.join(p)).unwrap();
+        let tuning = BdiTuning::apply(&system, makedev(8, 1)).unwrap();
+        assert_eq!("1", read("bdi/max_ratio"));
+        assert_eq!("none", read("queue/scheduler"));
+        drop(tuning);
+        assert_eq!("100", read("bdi/max_ratio"));
+        assert_eq!("mq-deadline", read("queue/scheduler"));
+    }
+}